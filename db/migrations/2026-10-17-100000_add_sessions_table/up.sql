@@ -0,0 +1,11 @@
+CREATE TABLE sessions (
+    id VARCHAR(64) NOT NULL,
+    user VARCHAR(64) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    user_agent VARCHAR(255) NULL DEFAULT NULL,
+    PRIMARY KEY (id),
+    INDEX sessions_user (user),
+    FOREIGN KEY (user) REFERENCES users (username) ON DELETE CASCADE
+);
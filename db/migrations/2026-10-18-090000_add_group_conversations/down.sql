@@ -0,0 +1,3 @@
+DROP TABLE group_messages;
+DROP TABLE conversation_members;
+DROP TABLE conversations;
@@ -0,0 +1,28 @@
+CREATE TABLE conversations (
+    id SERIAL,
+    title VARCHAR(128) NOT NULL,
+    created_by VARCHAR(64) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE conversation_members (
+    conversation_id BIGINT UNSIGNED NOT NULL,
+    member VARCHAR(64) NOT NULL,
+    joined_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (conversation_id, member),
+    INDEX conversation_members_member (member),
+    FOREIGN KEY (conversation_id) REFERENCES conversations (id) ON DELETE CASCADE,
+    FOREIGN KEY (member) REFERENCES users (username) ON DELETE CASCADE
+);
+
+CREATE TABLE group_messages (
+    id SERIAL,
+    conversation_id BIGINT UNSIGNED NOT NULL,
+    sender VARCHAR(64) NOT NULL,
+    content VARCHAR(1024) NOT NULL,
+    sent_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX group_messages_conversation (conversation_id, id),
+    FOREIGN KEY (conversation_id) REFERENCES conversations (id) ON DELETE CASCADE
+);
@@ -0,0 +1,5 @@
+ALTER TABLE conversations
+    DROP COLUMN last_activity,
+    DROP COLUMN last_message_id;
+
+DROP TABLE conversation_heads;
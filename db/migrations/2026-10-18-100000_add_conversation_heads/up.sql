@@ -0,0 +1,50 @@
+CREATE TABLE conversation_heads (
+    user VARCHAR(64) NOT NULL,
+    peer VARCHAR(64) NOT NULL,
+    last_message_id BIGINT UNSIGNED NOT NULL,
+    last_activity TIMESTAMP NOT NULL,
+    unread_count INT UNSIGNED NOT NULL DEFAULT 0,
+    PRIMARY KEY (user, peer),
+    INDEX conversation_heads_last_activity (user, last_activity)
+);
+
+INSERT INTO conversation_heads (user, peer, last_message_id, last_activity, unread_count)
+SELECT
+    latest.user,
+    latest.peer,
+    latest.last_message_id,
+    messages.sent_at,
+    (
+        SELECT COUNT(*)
+        FROM messages AS unread
+        WHERE unread.sender = latest.peer
+            AND unread.receiver = latest.user
+            AND unread.sender <> unread.receiver
+            AND unread.read_at IS NULL
+    )
+FROM (
+    SELECT user, peer, MAX(id) AS last_message_id
+    FROM (
+        SELECT sender AS user, receiver AS peer, id FROM messages
+        UNION ALL
+        SELECT receiver AS user, sender AS peer, id FROM messages WHERE sender <> receiver
+    ) AS participations
+    GROUP BY user, peer
+) AS latest
+JOIN messages ON messages.id = latest.last_message_id;
+
+ALTER TABLE conversations
+    ADD COLUMN last_message_id BIGINT UNSIGNED NULL,
+    ADD COLUMN last_activity TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+UPDATE conversations SET last_activity = created_at;
+
+UPDATE conversations
+JOIN (
+    SELECT conversation_id, MAX(id) AS last_message_id
+    FROM group_messages
+    GROUP BY conversation_id
+) AS latest ON latest.conversation_id = conversations.id
+JOIN group_messages ON group_messages.id = latest.last_message_id
+SET conversations.last_message_id = latest.last_message_id,
+    conversations.last_activity = group_messages.sent_at;
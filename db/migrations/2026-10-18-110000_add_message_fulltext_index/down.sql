@@ -0,0 +1 @@
+DROP INDEX messages_content ON messages;
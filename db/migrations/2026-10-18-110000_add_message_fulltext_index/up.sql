@@ -0,0 +1 @@
+CREATE FULLTEXT INDEX messages_content ON messages (content);
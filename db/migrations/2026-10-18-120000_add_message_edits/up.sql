@@ -0,0 +1,11 @@
+ALTER TABLE messages ADD COLUMN edited_at TIMESTAMP NULL DEFAULT NULL;
+
+CREATE TABLE message_edits (
+    id SERIAL,
+    message_id BIGINT UNSIGNED NOT NULL,
+    content VARCHAR(1024) NOT NULL,
+    edited_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX message_edits_message (message_id, id),
+    FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE
+);
@@ -0,0 +1,5 @@
+DROP TABLE hidden_messages;
+
+ALTER TABLE messages
+    DROP COLUMN deleted_by,
+    DROP COLUMN deleted_at;
@@ -0,0 +1,12 @@
+ALTER TABLE messages
+    ADD COLUMN deleted_at TIMESTAMP NULL DEFAULT NULL,
+    ADD COLUMN deleted_by VARCHAR(64) NULL DEFAULT NULL;
+
+CREATE TABLE hidden_messages (
+    user VARCHAR(64) NOT NULL,
+    message_id BIGINT UNSIGNED NOT NULL,
+    hidden_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user, message_id),
+    FOREIGN KEY (user) REFERENCES users (username) ON DELETE CASCADE,
+    FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+DROP TABLE reactions;
+
+ALTER TABLE messages DROP COLUMN reacted_at;
@@ -0,0 +1,11 @@
+ALTER TABLE messages ADD COLUMN reacted_at TIMESTAMP NULL DEFAULT NULL;
+
+CREATE TABLE reactions (
+    message_id BIGINT UNSIGNED NOT NULL,
+    user VARCHAR(64) NOT NULL,
+    emoji VARCHAR(16) NOT NULL,
+    reacted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (message_id, user, emoji),
+    FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE,
+    FOREIGN KEY (user) REFERENCES users (username) ON DELETE CASCADE
+);
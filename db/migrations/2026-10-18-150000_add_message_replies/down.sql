@@ -0,0 +1,3 @@
+ALTER TABLE messages
+    DROP FOREIGN KEY messages_reply_to,
+    DROP COLUMN reply_to;
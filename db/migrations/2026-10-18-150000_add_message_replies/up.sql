@@ -0,0 +1,3 @@
+ALTER TABLE messages
+    ADD COLUMN reply_to BIGINT UNSIGNED NULL DEFAULT NULL,
+    ADD CONSTRAINT messages_reply_to FOREIGN KEY (reply_to) REFERENCES messages (id) ON DELETE SET NULL;
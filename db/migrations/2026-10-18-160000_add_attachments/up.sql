@@ -0,0 +1,12 @@
+CREATE TABLE attachments (
+    id SERIAL,
+    message_id BIGINT UNSIGNED NOT NULL,
+    file_name VARCHAR(255) NOT NULL,
+    content_type VARCHAR(127) NOT NULL,
+    size BIGINT UNSIGNED NOT NULL,
+    -- Hex encoded SHA-256 of the content, which is also the name of the file on disk.
+    hash CHAR(64) NOT NULL,
+    PRIMARY KEY (id),
+    INDEX attachments_message (message_id, id),
+    FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE
+);
@@ -0,0 +1,14 @@
+CREATE TABLE mentions (
+    id SERIAL,
+    user VARCHAR(64) NOT NULL,
+    message_id BIGINT UNSIGNED NULL DEFAULT NULL,
+    group_message_id BIGINT UNSIGNED NULL DEFAULT NULL,
+    mentioned_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    read_at TIMESTAMP NULL DEFAULT NULL,
+    PRIMARY KEY (id),
+    INDEX mentions_user (user, id),
+    FOREIGN KEY (user) REFERENCES users (username) ON DELETE CASCADE,
+    FOREIGN KEY (message_id) REFERENCES messages (id) ON DELETE CASCADE,
+    FOREIGN KEY (group_message_id) REFERENCES group_messages (id) ON DELETE CASCADE,
+    CHECK ((message_id IS NULL) <> (group_message_id IS NULL))
+);
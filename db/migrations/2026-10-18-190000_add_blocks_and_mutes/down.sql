@@ -0,0 +1,3 @@
+ALTER TABLE conversation_heads DROP COLUMN muted;
+
+DROP TABLE blocks;
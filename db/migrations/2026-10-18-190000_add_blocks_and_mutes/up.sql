@@ -0,0 +1,10 @@
+CREATE TABLE blocks (
+    user VARCHAR(64) NOT NULL,
+    peer VARCHAR(64) NOT NULL,
+    blocked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user, peer),
+    FOREIGN KEY (user) REFERENCES users (username) ON DELETE CASCADE,
+    FOREIGN KEY (peer) REFERENCES users (username) ON DELETE CASCADE
+);
+
+ALTER TABLE conversation_heads ADD COLUMN muted BOOLEAN NOT NULL DEFAULT FALSE;
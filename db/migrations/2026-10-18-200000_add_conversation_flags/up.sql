@@ -0,0 +1,8 @@
+CREATE TABLE conversation_flags (
+    user VARCHAR(64) NOT NULL,
+    peer VARCHAR(64) NOT NULL,
+    pinned BOOLEAN NOT NULL DEFAULT FALSE,
+    archived BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (user, peer),
+    FOREIGN KEY (user) REFERENCES users (username) ON DELETE CASCADE
+);
@@ -0,0 +1,15 @@
+CREATE TABLE scheduled_messages (
+    id SERIAL,
+    sender VARCHAR(64) NOT NULL,
+    receiver VARCHAR(64) NOT NULL,
+    content VARCHAR(1024) NOT NULL,
+    reply_to BIGINT UNSIGNED NULL DEFAULT NULL,
+    send_at TIMESTAMP NOT NULL,
+    scheduled_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX scheduled_messages_send_at (send_at),
+    INDEX scheduled_messages_sender (sender, send_at),
+    FOREIGN KEY (sender) REFERENCES users (username) ON DELETE CASCADE,
+    FOREIGN KEY (receiver) REFERENCES users (username) ON DELETE CASCADE,
+    FOREIGN KEY (reply_to) REFERENCES messages (id) ON DELETE SET NULL
+);
@@ -0,0 +1,7 @@
+ALTER TABLE messages
+    DROP INDEX messages_expires_at,
+    DROP COLUMN expires_at,
+    DROP COLUMN disappear_after,
+    DROP COLUMN notice;
+
+DROP TABLE disappearing_timers;
@@ -0,0 +1,19 @@
+-- One row per conversation, keyed by its participants in alphabetical order, ignoring case.
+CREATE TABLE disappearing_timers (
+    user_a VARCHAR(64) NOT NULL,
+    user_b VARCHAR(64) NOT NULL,
+    duration_secs INT UNSIGNED NOT NULL,
+    from_read BOOLEAN NOT NULL DEFAULT FALSE,
+    set_by VARCHAR(64) NOT NULL,
+    set_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_a, user_b),
+    FOREIGN KEY (user_a) REFERENCES users (username) ON DELETE CASCADE,
+    FOREIGN KEY (user_b) REFERENCES users (username) ON DELETE CASCADE,
+    CHECK (user_a <= user_b)
+);
+
+ALTER TABLE messages
+    ADD COLUMN notice BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN disappear_after INT UNSIGNED NULL DEFAULT NULL,
+    ADD COLUMN expires_at TIMESTAMP NULL DEFAULT NULL,
+    ADD INDEX messages_expires_at (expires_at);
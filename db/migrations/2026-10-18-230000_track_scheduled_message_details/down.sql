@@ -0,0 +1,3 @@
+ALTER TABLE scheduled_messages
+    DROP COLUMN timezone_offset,
+    DROP COLUMN failed_at;
@@ -0,0 +1,3 @@
+ALTER TABLE scheduled_messages
+    ADD COLUMN timezone_offset SMALLINT NOT NULL DEFAULT 0 AFTER send_at,
+    ADD COLUMN failed_at TIMESTAMP NULL DEFAULT NULL;
@@ -1,31 +1,61 @@
-use std::{borrow::Cow, collections::HashMap, future::Future, pin::Pin};
+use std::{borrow::Cow, collections::HashMap, future::Future, path::PathBuf, pin::Pin};
 
 use askama::Template;
 use axum::{
-    extract::FromRequestParts,
+    extract::{FromRef, FromRequestParts},
     http::{
         header::{InvalidHeaderName, InvalidHeaderValue},
         request::Parts,
         HeaderMap, HeaderName, HeaderValue, StatusCode,
     },
+    middleware,
     response::{IntoResponse, IntoResponseParts, Redirect},
     routing::get,
     Router,
 };
+use axum_extra::extract::cookie::Key;
 use diesel_async::{pooled_connection::deadpool::Pool, AsyncMysqlConnection};
 use serde::{Serialize, Serializer};
 use serde_json::value::Serializer as JsonSerializer;
 use tower_http::services::ServeDir;
 
 mod conversations;
+mod error;
+mod hub;
 mod login;
+mod presence;
+mod scheduler;
+mod sweeper;
+mod typing;
 
-use conversations::MessagesPage;
-use login::{LoginPage, Username};
+use conversations::{
+    mentions::MentionsPage, scheduled::ScheduledPage, search::SearchPage, MessagesPage,
+};
+pub use error::AppError;
+use error::ErrorMessage;
+pub use hub::{Hub, Notification};
+use login::{LoginPage, RegisterPage, SessionsPage, Username};
+pub use presence::Presence;
+pub use scheduler::Scheduler;
+pub use sweeper::sweep_periodically;
+pub use typing::Typing;
 
 #[derive(Clone)]
 pub struct Application {
     pub db: Pool<AsyncMysqlConnection>,
+    pub key: Key,
+    pub hub: Hub,
+    pub typing: Typing,
+    pub presence: Presence,
+    pub scheduler: Scheduler,
+    /// Where attachments are stored.
+    pub attachments: PathBuf,
+}
+
+impl FromRef<Application> for Key {
+    fn from_ref(app: &Application) -> Self {
+        app.key.clone()
+    }
 }
 
 pub fn router() -> Router<Application> {
@@ -33,13 +63,23 @@ pub fn router() -> Router<Application> {
         .nest_service("/static", ServeDir::new("static/"))
         .nest("/login", login::router())
         .nest("/conversations", conversations::router())
+        .nest("/search", conversations::search::router())
+        .nest("/mentions", conversations::mentions::router())
+        .nest("/scheduled", conversations::scheduled::router())
         .route("/", get(|| async { Redirect::permanent("/conversations") }))
         .fallback(|| async { (StatusCode::NOT_FOUND, "Not a valid url on this server!") })
+        .layer(middleware::from_fn(error::render_errors))
 }
 
 enum Content {
     Login(LoginPage),
+    Register(RegisterPage),
+    Sessions(SessionsPage),
     Messages(MessagesPage),
+    Search(SearchPage),
+    Mentions(MentionsPage),
+    Scheduled(ScheduledPage),
+    Error(ErrorMessage),
 }
 
 #[derive(Template)]
@@ -67,9 +107,9 @@ impl<A: Send + Sync> FromRequestParts<A> for HtmxRequest {
     {
         Box::pin(async {
             let headers = HeaderMap::from_request_parts(parts, state).await.unwrap();
-            if !headers
+            if headers
                 .get("HX-Request")
-                .is_some_and(|value| value.as_bytes() == "true".as_bytes())
+                .is_none_or(|value| value.as_bytes() != "true".as_bytes())
             {
                 return Err((
                     StatusCode::BAD_REQUEST,
@@ -77,11 +117,11 @@ impl<A: Send + Sync> FromRequestParts<A> for HtmxRequest {
                 ));
             };
 
-            return Ok(Self {
+            Ok(Self {
                 restore: headers
                     .get("HX-History-Restore-Request")
                     .is_some_and(|value| value.as_bytes() == "true".as_bytes()),
-            });
+            })
         })
     }
 }
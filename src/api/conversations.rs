@@ -1,26 +1,127 @@
 use askama::Template;
 use axum::{routing::get, Router};
+use chrono::{Duration, Utc};
 
-use super::{Application, Content, Root, Username};
+use crate::model::UserPresence;
 
+use super::{
+    presence::{AWAY_WITHIN_SECS, ONLINE_WITHIN_SECS},
+    Application, Content, Presence, Root, Username,
+};
+
+pub mod attachments;
 mod direct;
+mod group;
 mod list;
+mod markdown;
+pub mod mentions;
+pub mod scheduled;
+pub mod search;
 
 pub fn router() -> Router<Application> {
     Router::new()
         .route("/", get(get_conversations))
         .nest("/list", list::router())
         .nest("/direct", direct::router())
+        .nest("/group", group::router())
 }
 
 #[derive(Template, Default)]
 #[template(path = "conversations/index.html")]
 pub struct MessagesPage {
+    user: String,
+    /// URL of the conversation to load right away.
     selected: Option<String>,
 }
 
-pub async fn get_conversations(_username: Username) -> Root {
+/// A user's initial, standing in for a profile picture.
+#[derive(Template, Debug, Clone)]
+#[template(path = "conversations/avatar.html")]
+pub struct Avatar {
+    name: String,
+    initial: String,
+}
+
+impl Avatar {
+    pub fn new(name: String) -> Self {
+        let initial = name
+            .chars()
+            .next()
+            .map(|initial| initial.to_uppercase().collect())
+            .unwrap_or_default();
+
+        Self { name, initial }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Status {
+    Online,
+    Away,
+    #[default]
+    Offline,
+}
+
+impl Status {
+    fn class(&self) -> &'static str {
+        match self {
+            Status::Online => "online",
+            Status::Away => "away",
+            Status::Offline => "offline",
+        }
+    }
+}
+
+/// A dot showing whether a user is online, with when they were last seen if they aren't, unless
+/// they keep that to themselves.
+#[derive(Template, Debug, Clone, Default)]
+#[template(path = "conversations/presence.html")]
+pub struct PresenceDot {
+    status: Status,
+    last_seen: Option<String>,
+}
+
+impl PresenceDot {
+    pub fn new(presence: &Presence, user: &UserPresence) -> Self {
+        if user.hide_last_seen {
+            return Self::default();
+        }
+        let Some(last_seen) = presence.last_seen(&user.username, user.last_seen_at) else {
+            return Self::default();
+        };
+
+        let elapsed = Utc::now().naive_utc() - last_seen;
+        let status = if elapsed < Duration::seconds(ONLINE_WITHIN_SECS) {
+            Status::Online
+        } else if elapsed < Duration::seconds(AWAY_WITHIN_SECS) {
+            Status::Away
+        } else {
+            Status::Offline
+        };
+
+        Self {
+            status,
+            last_seen: (status != Status::Online)
+                .then(|| format!("last seen {}", time_ago(elapsed))),
+        }
+    }
+}
+
+fn time_ago(elapsed: Duration) -> String {
+    match elapsed.num_minutes() {
+        ..=0 => "just now".to_owned(),
+        minutes @ 1..=59 => format!("{minutes} min ago"),
+        minutes @ 60..=1439 => format!("{} h ago", minutes / 60),
+        1440..=2879 => "yesterday".to_owned(),
+        minutes => format!("{} days ago", minutes / 1440),
+    }
+}
+
+pub async fn get_conversations(username: Username) -> Root {
     Root {
-        content: Content::Messages(MessagesPage { selected: None }),
+        content: Content::Messages(MessagesPage {
+            user: username.into_inner(),
+            selected: None,
+        }),
     }
 }
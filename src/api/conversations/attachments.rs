@@ -0,0 +1,416 @@
+use std::{
+    io::Cursor,
+    path::{Path as FsPath, PathBuf},
+};
+
+use askama::Template;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+    response::IntoResponse,
+};
+use diesel::OptionalExtension;
+use diesel_async::{AsyncMysqlConnection, RunQueryDsl};
+use image::{
+    io::{Limits, Reader as ImageReader},
+    DynamicImage, ImageFormat,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::{login::Username, AppError, Application},
+    model::{Attachment, Message as DbMessage, NewAttachment},
+};
+
+use super::direct::conversation_url;
+
+pub const MAX_ATTACHMENT_SIZE: usize = 8 * 1024 * 1024;
+pub const MAX_ATTACHMENTS: usize = 4;
+/// The largest request body accepted along with attachments, leaving room for the other fields.
+pub const MAX_UPLOAD_SIZE: usize = MAX_ATTACHMENTS * MAX_ATTACHMENT_SIZE + 64 * 1024;
+
+/// The content types attachments may have. Anything that a browser would run, like HTML or SVG,
+/// is left out on purpose.
+pub const ALLOWED_TYPES: [&str; 6] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "text/plain",
+];
+
+/// The bounding boxes, in pixels, that thumbnails of image attachments are generated for.
+pub const THUMBNAIL_SIZES: [u32; 2] = [256, 1024];
+
+/// Keeps decoding within reasonable memory, even for images that claim to be huge.
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+fn is_image(content_type: &str) -> bool {
+    content_type.starts_with("image/")
+}
+
+/// The format images of the given type and their thumbnails are encoded in, keeping transparency
+/// where the original may have some.
+fn thumbnail_format(content_type: &str) -> (ImageFormat, &'static str) {
+    match content_type {
+        "image/jpeg" => (ImageFormat::Jpeg, "image/jpeg"),
+        _ => (ImageFormat::Png, "image/png"),
+    }
+}
+
+/// `image` converted so that it can be encoded in `format`, JPEG having no alpha channel.
+fn encoded(image: &DynamicImage, format: ImageFormat) -> DynamicImage {
+    match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+        _ => image.clone(),
+    }
+}
+
+fn thumbnail_path(dir: &FsPath, hash: &str, size: u32) -> PathBuf {
+    dir.join(format!("{hash}-{size}"))
+}
+
+/// Removes the content stored under `hash` along with its thumbnails, once no attachment refers
+/// to it anymore.
+pub async fn remove_files(dir: &FsPath, hash: &str) -> std::io::Result<()> {
+    let paths = std::iter::once(dir.join(hash))
+        .chain(THUMBNAIL_SIZES.map(|size| thumbnail_path(dir, hash, size)));
+    for path in paths {
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Names content after itself, so that the same content is only stored once.
+fn hash(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// A file uploaded along with a new message, checked against the limits but not stored yet.
+#[derive(Debug)]
+pub struct Upload {
+    file_name: String,
+    content_type: String,
+    content: Bytes,
+    hash: String,
+    /// The decoded content of images, to generate thumbnails from.
+    image: Option<DynamicImage>,
+    /// Whether [`Upload::store`] wrote the content, rather than finding it stored already.
+    stored: bool,
+}
+
+impl Upload {
+    pub fn new(file_name: String, content_type: String, content: Bytes) -> Result<Self, AppError> {
+        if content.len() > MAX_ATTACHMENT_SIZE {
+            return Err(AppError::Invalid("Attachments can be at most 8 MB large."));
+        }
+        if !ALLOWED_TYPES.contains(&content_type.as_str()) {
+            return Err(AppError::Invalid(
+                "Only images, PDFs and text files can be attached.",
+            ));
+        }
+
+        let file_name = file_name.chars().take(255).collect();
+        Ok(Self {
+            file_name,
+            content_type,
+            hash: hash(&content),
+            content,
+            image: None,
+            stored: false,
+        })
+    }
+
+    /// Makes sure that images can actually be decoded, and replaces them with a copy encoded from
+    /// their pixels alone, so that neither the original nor its thumbnails carry EXIF data like
+    /// the location a photo was taken at. This happens on a blocking thread.
+    pub async fn decode(&mut self) -> Result<(), AppError> {
+        let Some(format) = ImageFormat::from_mime_type(&self.content_type) else {
+            return Ok(());
+        };
+
+        let content = self.content.clone();
+        let (output, content_type) = thumbnail_format(&self.content_type);
+        let decoded = tokio::task::spawn_blocking(move || {
+            let mut reader = ImageReader::with_format(Cursor::new(content), format);
+            let mut limits = Limits::default();
+            limits.max_alloc = Some(MAX_DECODE_ALLOC);
+            reader.limits(limits);
+            let image = reader.decode()?;
+
+            let mut stripped = Cursor::new(Vec::new());
+            encoded(&image, output).write_to(&mut stripped, output)?;
+            Ok::<_, image::ImageError>((image, stripped.into_inner()))
+        })
+        .await;
+
+        match decoded {
+            Ok(Ok((image, stripped))) => {
+                self.content = stripped.into();
+                self.content_type = content_type.to_owned();
+                self.hash = hash(&self.content);
+                self.image = Some(image);
+                Ok(())
+            }
+            _ => Err(AppError::Invalid(
+                "One of the images could not be read. It may be damaged, or not an image at all.",
+            )),
+        }
+    }
+
+    /// Writes the content to `dir`, named after its hash, unless the same content is stored
+    /// there already.
+    pub async fn store(&mut self, dir: &FsPath) -> std::io::Result<()> {
+        let path = dir.join(&self.hash);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(());
+        }
+
+        // Written under a temporary name first, so that a file under the final name is complete.
+        let partial = dir.join(format!("{}.partial", self.hash));
+        tokio::fs::write(&partial, &self.content).await?;
+        tokio::fs::rename(partial, path).await?;
+        self.stored = true;
+
+        Ok(())
+    }
+
+    /// Removes what [`Upload::store`] wrote for a message that couldn't be sent after all, unless
+    /// an attachment with the same content was sent in the meantime.
+    pub async fn discard(
+        &self,
+        db: &mut AsyncMysqlConnection,
+        dir: &FsPath,
+    ) -> Result<(), AppError> {
+        if !self.stored {
+            return Ok(());
+        }
+
+        let used = Attachment::with_hashes(vec![self.hash.clone()])
+            .first(db)
+            .await
+            .optional()?
+            .is_some();
+        if !used {
+            remove_files(dir, &self.hash).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Generates the thumbnails of images in the background, once the original is stored.
+    pub fn generate_thumbnails(&mut self, dir: PathBuf) {
+        let Some(image) = self.image.take() else {
+            return;
+        };
+        let (hash, content_type) = (self.hash.clone(), self.content_type.clone());
+
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = write_thumbnails(&image, &dir, &hash, &content_type) {
+                AppError::from(e).log();
+            }
+        });
+    }
+
+    pub fn attach_to(&self, message_id: u64) -> NewAttachment {
+        NewAttachment {
+            message_id,
+            file_name: self.file_name.clone(),
+            content_type: self.content_type.clone(),
+            size: self.content.len() as u64,
+            hash: self.hash.clone(),
+        }
+    }
+}
+
+/// Writes downscaled copies of `image` for each of the [`THUMBNAIL_SIZES`]. Images are encoded
+/// from their pixels alone, which leaves EXIF and other metadata behind.
+fn write_thumbnails(
+    image: &DynamicImage,
+    dir: &FsPath,
+    hash: &str,
+    content_type: &str,
+) -> image::ImageResult<()> {
+    let (format, _) = thumbnail_format(content_type);
+
+    for size in THUMBNAIL_SIZES {
+        let path = thumbnail_path(dir, hash, size);
+        if path.exists() {
+            continue;
+        }
+
+        let thumbnail = if image.width() <= size && image.height() <= size {
+            encoded(image, format)
+        } else {
+            encoded(&image.thumbnail(size, size), format)
+        };
+
+        let partial = dir.join(format!("{hash}-{size}.partial"));
+        thumbnail.save_with_format(&partial, format)?;
+        std::fs::rename(partial, path)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Template, Debug, Clone)]
+#[template(path = "conversations/direct/attachment.html")]
+pub struct AttachmentView {
+    url: String,
+    file_name: String,
+    size: String,
+    image: bool,
+}
+
+impl AttachmentView {
+    /// An attachment of a message exchanged with `peer`.
+    pub fn new(attachment: Attachment, peer: &str) -> Self {
+        Self {
+            url: format!("{}/attachments/{}", conversation_url(peer), attachment.id),
+            image: is_image(&attachment.content_type),
+            size: human_size(attachment.size),
+            file_name: attachment.file_name,
+        }
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{bytes} B"),
+        1024..=1048575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1048576.0),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttachmentPath {
+    peer: String,
+    id: u64,
+}
+
+/// The attachment with the given ID, as long as `user` took part in the conversation it was sent
+/// in and it wasn't deleted.
+async fn find_attachment(
+    db: &mut AsyncMysqlConnection,
+    (peer, user): (&str, &str),
+    id: u64,
+) -> Result<Attachment, AppError> {
+    let attachment = Attachment::with_id(id).first(db).await?;
+    let message = DbMessage::find_between((peer, user), attachment.message_id)
+        .first(db)
+        .await?;
+    if message.deleted_at.is_some() {
+        return Err(AppError::NotFound);
+    }
+
+    Ok(attachment)
+}
+
+/// Sends an attachment to either participant of the conversation it was sent in.
+pub async fn download(
+    State(Application {
+        db, attachments, ..
+    }): State<Application>,
+    Path(AttachmentPath { peer, id }): Path<AttachmentPath>,
+    username: Username,
+) -> Result<impl IntoResponse, AppError> {
+    let attachment = find_attachment(&mut *db.get().await?, (&peer, &username), id).await?;
+
+    let content = tokio::fs::read(attachments.join(&attachment.hash)).await?;
+
+    let disposition = if is_image(&attachment.content_type) {
+        "inline"
+    } else {
+        "attachment"
+    };
+
+    Ok((
+        [
+            (CONTENT_TYPE, attachment.content_type),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "{disposition}; filename*=UTF-8''{}",
+                    urlencoding::encode(&attachment.file_name)
+                ),
+            ),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            // Attachments never change, since they are named after their content.
+            (
+                CACHE_CONTROL,
+                "private, max-age=31536000, immutable".to_owned(),
+            ),
+        ],
+        content,
+    ))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThumbnailPath {
+    peer: String,
+    id: u64,
+    size: u32,
+}
+
+/// Sends a thumbnail of an image attachment, or the original while the thumbnail is still being
+/// generated, which was stripped of its metadata just the same when it was uploaded.
+pub async fn thumbnail(
+    State(Application {
+        db, attachments, ..
+    }): State<Application>,
+    Path(ThumbnailPath { peer, id, size }): Path<ThumbnailPath>,
+    username: Username,
+) -> Result<impl IntoResponse, AppError> {
+    if !THUMBNAIL_SIZES.contains(&size) {
+        return Err(AppError::NotFound);
+    }
+
+    let attachment = find_attachment(&mut *db.get().await?, (&peer, &username), id).await?;
+    if !is_image(&attachment.content_type) {
+        return Err(AppError::NotFound);
+    }
+
+    let (content_type, content) =
+        match tokio::fs::read(thumbnail_path(&attachments, &attachment.hash, size)).await {
+            Ok(content) => (thumbnail_format(&attachment.content_type).1, content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+                attachment.content_type.as_str(),
+                tokio::fs::read(attachments.join(&attachment.hash)).await?,
+            ),
+            Err(e) => return Err(e.into()),
+        };
+
+    Ok((
+        [
+            (CONTENT_TYPE, content_type.to_owned()),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            (CACHE_CONTROL, "private, no-cache".to_owned()),
+        ],
+        content,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::human_size;
+
+    #[test]
+    fn sizes_use_the_largest_fitting_unit() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KB");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(1024 * 1024), "1.0 MB");
+        assert_eq!(human_size(8 * 1024 * 1024), "8.0 MB");
+    }
+}
@@ -1,206 +1,2081 @@
-use std::{
-    borrow::{BorrowMut, Cow},
-    fmt::Display,
+use std::{collections::HashMap, convert::Infallible, fmt::Display};
+
+use askama::Template;
+use axum::{
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive},
+        Sse,
+    },
+    routing::{delete, get, post, put},
+    Form, Router,
+};
+use axum_extra::either::Either;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel::{dsl::now, ExpressionMethods, Insertable, OptionalExtension, QueryDsl, QueryResult};
+use diesel_async::{
+    pooled_connection::deadpool::Pool, scoped_futures::ScopedFutureExt, AsyncConnection,
+    AsyncMysqlConnection, RunQueryDsl,
+};
+use futures::Stream;
+use serde::Deserialize;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::{
+    api::{
+        login::Username,
+        typing::{Typing, TYPING_TTL},
+        AppError, Application, Content, HtmxRequest, Hub, HxTrigger, Notification, Presence, Root,
+    },
+    model::{
+        schema::{
+            attachments, blocks, conversation_flags, conversation_heads, disappearing_timers,
+            hidden_messages, mentions, message_edits, messages::dsl, reactions, scheduled_messages,
+        },
+        Attachment, Block, ConversationFlags, ConversationHead, DisappearingTimer, GroupMessage,
+        Mention, Message as DbMessage, MessageEdit, NewBlock, NewConversationFlags,
+        NewConversationHead, NewDisappearingTimer, NewHiddenMessage, NewMention, NewMessage,
+        NewMessageEdit, NewReaction, NewScheduledMessage, Reaction, User, UserPresence,
+    },
+};
+
+use super::{
+    attachments::{self as files, AttachmentView, Upload, MAX_ATTACHMENTS, MAX_UPLOAD_SIZE},
+    list::MAX_PINNED,
+    markdown, Avatar, MessagesPage, PresenceDot,
 };
 
-use askama::Template;
-use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
-    routing::{get, post},
-    Form, Router,
-};
-use axum_extra::either::Either;
-use diesel::{Insertable, OptionalExtension};
-use diesel_async::RunQueryDsl;
-use serde::Deserialize;
+pub const MESSAGE_LIMIT: usize = 10;
+
+/// The emoji messages can be reacted with.
+pub const REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🎉"];
+
+pub fn router() -> Router<Application> {
+    Router::new()
+        .route("/:peer", get(get_conversation))
+        .route(
+            "/:peer",
+            post(send_message).layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE)),
+        )
+        .route("/:peer/poll", get(get_new_messages))
+        .route("/:peer/events", get(message_events))
+        .route("/:peer/read", post(mark_conversation_read))
+        .route("/:peer/typing", get(typing_indicator).post(announce_typing))
+        .route("/:peer/presence", get(peer_presence))
+        .route("/:peer/block", post(block_peer).delete(unblock_peer))
+        .route(
+            "/:peer/pin",
+            post(pin_conversation).delete(unpin_conversation),
+        )
+        .route("/:peer/timer", put(set_timer))
+        .route(
+            "/:peer/archive",
+            post(archive_conversation).delete(unarchive_conversation),
+        )
+        .route(
+            "/:peer/mute",
+            post(mute_conversation).delete(unmute_conversation),
+        )
+        .route("/:peer/search", get(search))
+        .route("/:peer/messages/:id", put(edit_message))
+        .route("/:peer/messages/:id", delete(delete_message))
+        .route("/:peer/messages/:id/hide", post(hide_message))
+        .route("/:peer/messages/:id/reactions", post(toggle_reaction))
+        .route("/:peer/messages/:id/reply", get(reply_target))
+        .route("/:peer/attachments/:id", get(files::download))
+        .route(
+            "/:peer/attachments/:id/thumbnails/:size",
+            get(files::thumbnail),
+        )
+        .route("/:peer/messages/:id/history", get(message_history))
+        .route("/:peer/:direction", get(load_more))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetConversation {
+    peer: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetConversationQuery {
+    /// Shows the conversation around this message instead of its newest messages.
+    message: Option<u64>,
+}
+
+#[derive(Template, Default)]
+#[template(path = "conversations/direct/hidden-refresh.html")]
+pub struct AutoRefreshMessages {
+    messages: Vec<Message>,
+    /// Previously sent messages that were edited, deleted or reacted to since the last refresh.
+    changed: Vec<Message>,
+    last_seen_message_id: Option<u64>,
+    /// Unix timestamp from which on the next refresh picks up edits.
+    refreshed_at: i64,
+    url: String,
+}
+
+impl AutoRefreshMessages {
+    pub fn new(messages: Vec<Message>, peer: &str) -> Self {
+        let last_seen_message_id = messages.as_slice().first().map(|message| message.id);
+
+        Self::with_url(messages, last_seen_message_id, conversation_url(peer))
+    }
+
+    /// Newest first, polling `{url}/poll` for more.
+    pub fn with_url(
+        messages: Vec<Message>,
+        last_seen_message_id: Option<u64>,
+        url: String,
+    ) -> Self {
+        Self {
+            messages,
+            changed: Vec::new(),
+            last_seen_message_id,
+            refreshed_at: Utc::now().timestamp(),
+            url,
+        }
+    }
+
+    /// Replaces the bubbles of the `changed` messages as well.
+    pub fn with_changed(self, changed: Vec<Message>) -> Self {
+        Self {
+            changed: changed
+                .into_iter()
+                .map(|message| Message {
+                    oob: true,
+                    ..message
+                })
+                .collect(),
+            ..self
+        }
+    }
+
+    pub fn last_seen_message_id(&self) -> Option<u64> {
+        self.last_seen_message_id
+    }
+}
+
+pub fn conversation_url(peer: &str) -> String {
+    format!("/conversations/direct/{peer}")
+}
+
+#[derive(Template, Default)]
+#[template(path = "conversations/direct/conversation-details.html")]
+pub struct ConversationView {
+    peer: String,
+    presence: PresenceDot,
+    actions: ConversationActions,
+    timer: TimerControl,
+    typing: TypingIndicator,
+    messages: AutoRefreshMessages,
+    lazy_load: Option<LoadMore>,
+    focused: Option<Positioned>,
+}
+
+/// Blocking the peer and muting, pinning or archiving the conversation, or undoing any of them.
+#[derive(Template, Default)]
+#[template(path = "conversations/direct/conversation-actions.html")]
+pub struct ConversationActions {
+    peer: String,
+    /// Since when the peer is blocked, if they are.
+    blocked: Option<String>,
+    /// Only conversations with messages in them can be muted, pinned or archived.
+    has_messages: bool,
+    muted: bool,
+    flags: ConversationFlags,
+}
+
+impl ConversationActions {
+    async fn load(db: &mut AsyncMysqlConnection, (peer, user): (&str, &str)) -> QueryResult<Self> {
+        let head = ConversationHead::between(user, peer)
+            .first(db)
+            .await
+            .optional()?;
+        let blocked = Block::between(user, peer).first(db).await.optional()?;
+        let flags = ConversationFlags::between(user, peer)
+            .first(db)
+            .await
+            .optional()?;
+
+        Ok(Self {
+            peer: peer.to_owned(),
+            blocked: blocked.map(|block| block.blocked_at.to_string()),
+            has_messages: head.is_some(),
+            muted: head.is_some_and(|head| head.muted),
+            flags: flags.unwrap_or_default(),
+        })
+    }
+}
+
+/// The lengths a disappearing messages timer can be set to.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimerLength {
+    #[default]
+    Off,
+    Hour,
+    Day,
+    Week,
+}
+
+impl TimerLength {
+    const ALL: [TimerLength; 4] = [Self::Off, Self::Hour, Self::Day, Self::Week];
+
+    fn secs(self) -> Option<u32> {
+        match self {
+            Self::Off => None,
+            Self::Hour => Some(60 * 60),
+            Self::Day => Some(24 * 60 * 60),
+            Self::Week => Some(7 * 24 * 60 * 60),
+        }
+    }
+
+    fn value(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Hour => "hour",
+            Self::Day => "day",
+            Self::Week => "week",
+        }
+    }
+
+    fn label(&self) -> String {
+        self.secs().map_or("Off".to_owned(), describe_secs)
+    }
+}
+
+/// A whole number of days, hours or minutes, whichever fits `secs` best.
+fn describe_secs(secs: u32) -> String {
+    let (count, unit) = match secs {
+        _ if secs.is_multiple_of(24 * 60 * 60) => (secs / (24 * 60 * 60), "day"),
+        _ if secs.is_multiple_of(60 * 60) => (secs / (60 * 60), "hour"),
+        _ => (secs / 60, "minute"),
+    };
+
+    format!("{count} {unit}{}", if count == 1 { "" } else { "s" })
+}
+
+/// The disappearing messages timer of the conversation, which either participant can change.
+#[derive(Template, Default)]
+#[template(path = "conversations/direct/disappearing-timer.html")]
+pub struct TimerControl {
+    peer: String,
+    length: TimerLength,
+    /// Whether the countdown starts when a message is read rather than when it is sent.
+    from_read: bool,
+    /// Who set the timer and when, if it is on.
+    set: Option<(String, String)>,
+}
+
+impl TimerControl {
+    async fn load(db: &mut AsyncMysqlConnection, (peer, user): (&str, &str)) -> QueryResult<Self> {
+        let timer = DisappearingTimer::between((peer, user))
+            .first(db)
+            .await
+            .optional()?;
+
+        Ok(match timer {
+            Some(timer) => Self {
+                peer: peer.to_owned(),
+                length: TimerLength::ALL
+                    .into_iter()
+                    .find(|length| length.secs() == Some(timer.duration_secs))
+                    .unwrap_or_default(),
+                from_read: timer.from_read,
+                set: Some((timer.set_by, timer.set_at.to_string())),
+            },
+            None => Self {
+                peer: peer.to_owned(),
+                ..Default::default()
+            },
+        })
+    }
+
+    fn lengths(&self) -> [TimerLength; 4] {
+        TimerLength::ALL
+    }
+}
+
+/// Whether the peer is typing a message, which checks again once they would have stopped.
+#[derive(Template, Default)]
+#[template(path = "conversations/direct/typing-indicator.html")]
+pub struct TypingIndicator {
+    peer: String,
+    typing: bool,
+}
+
+impl TypingIndicator {
+    fn new(typing: &Typing, (peer, user): (&str, &str)) -> Self {
+        Self {
+            peer: peer.to_owned(),
+            typing: typing.is_typing(peer, user),
+        }
+    }
+
+    fn recheck_delay(&self) -> u64 {
+        TYPING_TTL.as_secs()
+    }
+}
+
+/// A single message with the messages around it loaded lazily in both directions.
+#[derive(Template, Debug)]
+#[template(path = "conversations/direct/positioned.html")]
+pub struct Positioned {
+    later: LoadMore,
+    message: Box<Message>,
+    earlier: LoadMore,
+}
+
+impl Positioned {
+    fn new(message: Message, url: String) -> Self {
+        Self {
+            later: LoadMore {
+                url: url.clone(),
+                id: message.id,
+                direction: LoadDirection::Later,
+            },
+            earlier: LoadMore {
+                url,
+                id: message.id,
+                direction: LoadDirection::Earlier,
+            },
+            message: Box::new(message),
+        }
+    }
+}
+
+#[derive(Template, Debug, Clone)]
+#[template(path = "conversations/direct/individual-message.html")]
+pub struct Message {
+    yours: bool,
+    id: u64,
+    author: Option<Avatar>,
+    content: String,
+    date: String,
+    seen: Option<String>,
+    edited: bool,
+    /// Deleted for everyone, leaving only a tombstone without content.
+    deleted: bool,
+    /// Where the message can be edited, deleted and its history looked at, if anywhere.
+    url: Option<String>,
+    oob: bool,
+    /// Search terms to highlight in the content.
+    highlight: Vec<String>,
+    reactions: Vec<ReactionChip>,
+    /// The earlier message this one replies to.
+    quote: Option<Quote>,
+    attachments: Vec<AttachmentView>,
+    /// A notice about the conversation, shown without any of the actions on messages.
+    notice: bool,
+    /// When the message disappears, or how long after being read.
+    disappears: Option<String>,
+}
+
+impl Message {
+    /// The content as sanitized HTML, with the highlighted search terms marked.
+    fn rendered(&self) -> String {
+        markdown::render(&self.content, &self.highlight)
+    }
+
+    fn reaction_choices(&self) -> &'static [&'static str] {
+        &REACTIONS
+    }
+}
+
+/// An excerpt of a message replied to, which leads to the message itself.
+#[derive(Template, Debug, Clone)]
+#[template(path = "conversations/direct/quote.html")]
+pub struct Quote {
+    id: u64,
+    author: String,
+    content: String,
+    deleted: bool,
+    url: String,
+}
+
+impl Quote {
+    fn new(message: DbMessage, user: &str) -> Self {
+        let peer = if message.sender == user {
+            &message.receiver
+        } else {
+            &message.sender
+        };
+
+        Self {
+            id: message.id,
+            url: conversation_url(peer),
+            author: if message.sender == user {
+                "You".to_owned()
+            } else {
+                message.sender
+            },
+            deleted: message.deleted_at.is_some(),
+            content: if message.deleted_at.is_some() {
+                String::new()
+            } else {
+                markdown::plain_text(&message.content)
+            },
+        }
+    }
+}
+
+/// How many users reacted to a message with one emoji.
+#[derive(Debug, Clone)]
+pub struct ReactionChip {
+    emoji: String,
+    count: usize,
+    yours: bool,
+}
+
+/// Turns `messages` into bubbles as seen by `user`, along with their attachments, the reactions
+/// to them and the messages they reply to.
+async fn bubbles(
+    db: &mut AsyncMysqlConnection,
+    user: &str,
+    messages: Vec<DbMessage>,
+) -> QueryResult<Vec<Message>> {
+    if messages.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut chips: HashMap<u64, Vec<ReactionChip>> = HashMap::new();
+    for reaction in Reaction::of_messages(messages.iter().map(|msg| msg.id).collect())
+        .load(db)
+        .await?
+    {
+        let yours = reaction.user == user;
+        let chips = chips.entry(reaction.message_id).or_default();
+        match chips.iter_mut().find(|chip| chip.emoji == reaction.emoji) {
+            Some(chip) => {
+                chip.count += 1;
+                chip.yours |= yours;
+            }
+            None => chips.push(ReactionChip {
+                emoji: reaction.emoji,
+                count: 1,
+                yours,
+            }),
+        }
+    }
+
+    let mut attached: HashMap<u64, Vec<Attachment>> = HashMap::new();
+    for attachment in Attachment::of_messages(messages.iter().map(|msg| msg.id).collect())
+        .load(db)
+        .await?
+    {
+        attached
+            .entry(attachment.message_id)
+            .or_default()
+            .push(attachment);
+    }
+
+    let quoted: HashMap<u64, DbMessage> =
+        DbMessage::with_ids(messages.iter().filter_map(|msg| msg.reply_to).collect())
+            .load(db)
+            .await?
+            .into_iter()
+            .map(|message| (message.id, message))
+            .collect();
+
+    Ok(messages
+        .into_iter()
+        .map(|msg| Message {
+            attachments: attached
+                .remove(&msg.id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|attachment| {
+                    let peer = if msg.sender == user {
+                        &msg.receiver
+                    } else {
+                        &msg.sender
+                    };
+                    AttachmentView::new(attachment, peer)
+                })
+                .collect(),
+            reactions: chips.remove(&msg.id).unwrap_or_default(),
+            quote: msg
+                .reply_to
+                .and_then(|id| quoted.get(&id))
+                .map(|quoted| Quote::new(quoted.clone(), user)),
+            ..(msg.sender == user, msg).into()
+        })
+        .collect())
+}
+
+async fn bubble(
+    db: &mut AsyncMysqlConnection,
+    user: &str,
+    message: DbMessage,
+) -> QueryResult<Message> {
+    let mut bubbles = bubbles(db, user, vec![message]).await?;
+    Ok(bubbles.remove(0))
+}
+
+/// Splits `haystack` into the parts that do and don't match any of the `needles`, ignoring case.
+pub fn split_matches<'a>(haystack: &'a str, needles: &[String]) -> Vec<(bool, &'a str)> {
+    let needles: Vec<Vec<char>> = needles
+        .iter()
+        .map(|needle| needle.chars().flat_map(char::to_lowercase).collect())
+        .filter(|needle: &Vec<char>| !needle.is_empty())
+        .collect();
+
+    let mut parts = Vec::new();
+    let (mut start, mut index) = (0, 0);
+    while let Some(c) = haystack[index..].chars().next() {
+        let Some(len) = needles
+            .iter()
+            .find_map(|needle| match_len(&haystack[index..], needle))
+        else {
+            index += c.len_utf8();
+            continue;
+        };
+
+        if start < index {
+            parts.push((false, &haystack[start..index]));
+        }
+        parts.push((true, &haystack[index..index + len]));
+        index += len;
+        start = index;
+    }
+    if start < haystack.len() {
+        parts.push((false, &haystack[start..]));
+    }
+
+    parts
+}
+
+/// The length in bytes of the prefix of `haystack` matching the lowercase `needle`, if any.
+fn match_len(haystack: &str, needle: &[char]) -> Option<usize> {
+    let mut remaining = needle;
+    for (index, c) in haystack.char_indices() {
+        for lower in c.to_lowercase() {
+            let (first, rest) = remaining.split_first()?;
+            if *first != lower {
+                return None;
+            }
+            remaining = rest;
+        }
+
+        if remaining.is_empty() {
+            return Some(index + c.len_utf8());
+        }
+    }
+
+    None
+}
+
+impl From<(bool, DbMessage)> for Message {
+    fn from((yours, msg): (bool, DbMessage)) -> Self {
+        let peer = if yours { &msg.receiver } else { &msg.sender };
+
+        Self {
+            yours,
+            url: Some(format!("{}/messages/{}", conversation_url(peer), msg.id)),
+            edited: msg.edited_at.is_some(),
+            deleted: msg.deleted_at.is_some(),
+            id: msg.id,
+            author: None,
+            content: if msg.deleted_at.is_some() {
+                String::new()
+            } else {
+                msg.content
+            },
+            date: msg.sent_at.to_string(),
+            seen: msg
+                .read_at
+                .filter(|_| yours)
+                .map(|read_at| read_at.to_string()),
+            oob: false,
+            highlight: Vec::new(),
+            reactions: Vec::new(),
+            quote: None,
+            attachments: Vec::new(),
+            notice: msg.notice,
+            disappears: match (msg.expires_at, msg.disappear_after) {
+                (Some(expires_at), _) => Some(format!("Disappears {expires_at}")),
+                (None, Some(secs)) => Some(format!(
+                    "Disappears {} after it is read",
+                    describe_secs(secs)
+                )),
+                (None, None) => None,
+            },
+        }
+    }
+}
+
+impl From<(bool, GroupMessage)> for Message {
+    fn from((yours, msg): (bool, GroupMessage)) -> Self {
+        Self {
+            yours,
+            id: msg.id,
+            author: (!yours).then(|| Avatar::new(msg.sender)),
+            content: msg.content,
+            date: msg.sent_at.to_string(),
+            seen: None,
+            edited: false,
+            deleted: false,
+            url: None,
+            oob: false,
+            highlight: Vec::new(),
+            reactions: Vec::new(),
+            quote: None,
+            attachments: Vec::new(),
+            notice: false,
+            disappears: None,
+        }
+    }
+}
+
+/// Marks those `messages` as read that were sent by `peer` to `username`, along with the mentions
+/// of `username` in them, and lets both of them know about it.
+async fn mark_read(
+    db: &mut AsyncMysqlConnection,
+    hub: &Hub,
+    (peer, username): (&str, &str),
+    messages: &[DbMessage],
+) -> QueryResult<()> {
+    let ids: Vec<u64> = messages
+        .iter()
+        .filter(|msg| msg.sender == peer && msg.receiver == username && msg.read_at.is_none())
+        .map(|msg| msg.id)
+        .collect();
+
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    // Countdowns are taken from the same clock as everything else about disappearing messages.
+    let read_at = Utc::now().naive_utc();
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        async {
+            // Only count messages that weren't read concurrently in the meantime.
+            let read = diesel::update(
+                dsl::messages
+                    .filter(dsl::id.eq_any(&ids))
+                    .filter(dsl::read_at.is_null()),
+            )
+            .set(dsl::read_at.eq(read_at))
+            .execute(db)
+            .await?;
+
+            // Messages under a timer counting from when they are read start disappearing now.
+            let mut countdowns: Vec<u32> = messages
+                .iter()
+                .filter(|msg| ids.contains(&msg.id) && msg.expires_at.is_none())
+                .filter_map(|msg| msg.disappear_after)
+                .collect();
+            countdowns.sort_unstable();
+            countdowns.dedup();
+            for secs in countdowns {
+                diesel::update(
+                    dsl::messages
+                        .filter(dsl::id.eq_any(&ids))
+                        .filter(dsl::disappear_after.eq(secs))
+                        .filter(dsl::expires_at.is_null()),
+                )
+                .set(dsl::expires_at.eq(read_at + Duration::seconds(secs.into())))
+                .execute(db)
+                .await?;
+            }
+
+            if peer != username && read > 0 {
+                diesel::update(conversation_heads::table.find((username, peer)))
+                    .set(
+                        conversation_heads::unread_count
+                            .eq(conversation_heads::unread_count - read as u32),
+                    )
+                    .execute(db)
+                    .await?;
+            }
+
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    let mentions_read =
+        diesel::update(Mention::unread(username).filter(mentions::message_id.eq_any(&ids)))
+            .set(mentions::read_at.eq(now))
+            .execute(db)
+            .await?;
+    if mentions_read > 0 {
+        hub.publish(username, Notification::MentionsChanged);
+    }
+
+    let notification = Notification::MessagesRead {
+        reader: username.to_owned(),
+        sender: peer.to_owned(),
+        ids,
+    };
+    if peer != username {
+        hub.publish(peer, notification.clone());
+    }
+    hub.publish(username, notification);
+
+    Ok(())
+}
+
+/// Moves the conversation `message` belongs to to the top of both participants' lists, counting
+/// it as unread for the receiver, or just the sender's list if it wasn't `delivered`. Either list
+/// takes the conversation back out of the archive.
+async fn advance_heads(
+    db: &mut AsyncMysqlConnection,
+    message: &DbMessage,
+    delivered: bool,
+) -> QueryResult<()> {
+    let participants = if message.sender == message.receiver || !delivered {
+        vec![(&message.sender, &message.receiver, 0)]
+    } else {
+        vec![
+            (&message.sender, &message.receiver, 0),
+            (&message.receiver, &message.sender, 1),
+        ]
+    };
+
+    for (user, peer, unread) in participants {
+        NewConversationHead {
+            user: user.clone(),
+            peer: peer.clone(),
+            last_message_id: message.id,
+            last_activity: message.sent_at,
+            unread_count: unread,
+        }
+        .upsert()
+        .execute(db)
+        .await?;
+
+        diesel::update(conversation_flags::table.find((user, peer)))
+            .set(conversation_flags::archived.eq(false))
+            .execute(db)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn get_conversation(
+    State(Application {
+        db,
+        hub,
+        typing,
+        presence,
+        ..
+    }): State<Application>,
+    htmx: Option<HtmxRequest>,
+    Path(GetConversation { peer }): Path<GetConversation>,
+    Query(GetConversationQuery { message }): Query<GetConversationQuery>,
+    username: Username,
+) -> Result<Either<Root, ConversationView>, AppError> {
+    if let None | Some(HtmxRequest { restore: true, .. }) = htmx {
+        let url = match message {
+            Some(id) => format!("{}?message={id}", conversation_url(&peer)),
+            None => conversation_url(&peer),
+        };
+
+        return Ok(Either::E1(Root {
+            content: Content::Messages(MessagesPage {
+                user: username.into_inner(),
+                selected: Some(url),
+            }),
+        }));
+    }
+
+    let mut db = db.get().await?;
+
+    let presence = presence_of(&mut db, &presence, &peer).await?;
+    let actions = ConversationActions::load(&mut db, (&peer, &username)).await?;
+    let timer = TimerControl::load(&mut db, (&peer, &username)).await?;
+
+    if let Some(id) = message {
+        let message = DbMessage::find_between((&peer, &username), id)
+            .first(&mut db)
+            .await?;
+        mark_read(
+            &mut db,
+            &hub,
+            (&peer, &username),
+            std::slice::from_ref(&message),
+        )
+        .await?;
+
+        return Ok(Either::E2(ConversationView {
+            focused: Some(Positioned::new(
+                bubble(&mut db, &username, message).await?,
+                conversation_url(&peer),
+            )),
+            typing: TypingIndicator::new(&typing, (&peer, &username)),
+            presence,
+            actions,
+            timer,
+            peer,
+            ..Default::default()
+        }));
+    }
+
+    let messages_in_convo = DbMessage::limited((&peer, &username), MESSAGE_LIMIT)
+        .load(db.as_mut())
+        .await?;
+
+    mark_read(&mut db, &hub, (&peer, &username), &messages_in_convo).await?;
+
+    let lazy_load = LoadMore::new(
+        LoadDirection::Earlier,
+        messages_in_convo.iter().map(|msg| msg.id),
+        conversation_url(&peer),
+    );
+
+    Ok(Either::E2(ConversationView {
+        messages: AutoRefreshMessages::new(
+            bubbles(&mut db, &username, messages_in_convo).await?,
+            &peer,
+        ),
+        lazy_load,
+        focused: None,
+        typing: TypingIndicator::new(&typing, (&peer, &username)),
+        presence,
+        actions,
+        timer,
+        peer,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SendMessagePath {
+    peer: String,
+}
+
+#[derive(Debug, Default)]
+pub struct SendMessageForm {
+    new_message_content: String,
+    last_seen_message_id: Option<u64>,
+    changed_since: Option<i64>,
+    reply_to: Option<u64>,
+    attachments: Vec<Upload>,
+    /// When to send the message instead of right away, in UTC.
+    send_at: Option<NaiveDateTime>,
+    /// How many minutes the browser's time zone is behind UTC, which `send_at` was picked in.
+    timezone_offset: i16,
+}
+
+impl SendMessageForm {
+    /// Reads the form from its `multipart/form-data` encoding, which it needs for attachments.
+    async fn read(mut multipart: Multipart) -> Result<Self, AppError> {
+        let mut form = Self::default();
+        while let Some(field) = multipart.next_field().await? {
+            match field.name() {
+                Some("new-message-content") => form.new_message_content = field.text().await?,
+                Some("last-seen-message-id") => {
+                    form.last_seen_message_id = field.text().await?.parse().ok()
+                }
+                Some("changed-since") => form.changed_since = field.text().await?.parse().ok(),
+                Some("reply-to") => form.reply_to = field.text().await?.parse().ok(),
+                Some("send-at") => {
+                    form.send_at =
+                        NaiveDateTime::parse_from_str(&field.text().await?, "%Y-%m-%dT%H:%M").ok()
+                }
+                Some("timezone-offset") => {
+                    form.timezone_offset = field.text().await?.parse().unwrap_or_default()
+                }
+                Some("attachments") => {
+                    let file_name = field.file_name().unwrap_or_default().to_owned();
+                    let content_type = field.content_type().unwrap_or_default().to_owned();
+                    let content = field.bytes().await?;
+                    // Browsers send an empty part when no file was picked.
+                    if file_name.is_empty() && content.is_empty() {
+                        continue;
+                    }
+                    if form.attachments.len() == MAX_ATTACHMENTS {
+                        return Err(AppError::Invalid(
+                            "Messages can have at most 4 attachments.",
+                        ));
+                    }
+                    form.attachments
+                        .push(Upload::new(file_name, content_type, content)?);
+                }
+                _ => {}
+            }
+        }
+        form.send_at = form
+            .send_at
+            .map(|send_at| send_at + Duration::minutes(form.timezone_offset.into()));
+
+        Ok(form)
+    }
+}
+
+/// The receiver of a message from `sender` with the given `content`, if it mentions them. Nobody
+/// but the peer gets to see the message, so mentioning anyone else goes nowhere.
+async fn mentioned_receiver(
+    db: &mut AsyncMysqlConnection,
+    (sender, receiver): (&str, &str),
+    content: &str,
+) -> QueryResult<Option<String>> {
+    if sender == receiver
+        || !markdown::mentioned(content)
+            .iter()
+            .any(|user| user == receiver)
+    {
+        return Ok(None);
+    }
+
+    Ok(User::named(receiver)
+        .first(db)
+        .await
+        .optional()?
+        .map(|user| user.username))
+}
+
+/// A message on its way to the receiver, along with what sending it involves.
+pub struct Outgoing {
+    message: NewMessage,
+    /// Messages to peers who blocked the sender look sent to them, but are hidden from the peer
+    /// right away.
+    blocked: bool,
+    /// The receiver, if the message mentions them.
+    mentioned: Option<String>,
+    /// The disappearing messages timer of the conversation, which notices aren't subject to.
+    timer: Option<DisappearingTimer>,
+}
+
+impl Outgoing {
+    pub async fn new(db: &mut AsyncMysqlConnection, message: NewMessage) -> QueryResult<Self> {
+        let blocked = Block::between(&message.receiver, &message.sender)
+            .first(db)
+            .await
+            .optional()?
+            .is_some();
+
+        let mentioned = if blocked {
+            None
+        } else {
+            mentioned_receiver(db, (&message.sender, &message.receiver), &message.content).await?
+        };
+
+        let timer = if message.notice {
+            None
+        } else {
+            DisappearingTimer::between((&message.receiver, &message.sender))
+                .first(db)
+                .await
+                .optional()?
+        };
+
+        Ok(Self {
+            message,
+            blocked,
+            mentioned,
+            timer,
+        })
+    }
+
+    /// Stores the message along with its already stored `uploads`, inside the caller's
+    /// transaction.
+    pub async fn store(
+        &self,
+        db: &mut AsyncMysqlConnection,
+        uploads: &[Upload],
+    ) -> QueryResult<DbMessage> {
+        let disappear_after = self.timer.as_ref().map(|timer| timer.duration_secs);
+        let expires_at = self
+            .timer
+            .as_ref()
+            .filter(|timer| !timer.from_read)
+            .map(|timer| Utc::now().naive_utc() + Duration::seconds(timer.duration_secs.into()));
+        (
+            &self.message,
+            dsl::disappear_after.eq(disappear_after),
+            dsl::expires_at.eq(expires_at),
+        )
+            .insert_into(dsl::messages)
+            .execute(db)
+            .await?;
+        let sent = DbMessage::last_inserted().first(db).await?;
+        if self.blocked {
+            NewHiddenMessage {
+                user: self.message.receiver.clone(),
+                message_id: sent.id,
+            }
+            .insert_into(hidden_messages::table)
+            .execute(db)
+            .await?;
+        }
+        if !uploads.is_empty() {
+            diesel::insert_into(attachments::table)
+                .values(
+                    uploads
+                        .iter()
+                        .map(|upload| upload.attach_to(sent.id))
+                        .collect::<Vec<_>>(),
+                )
+                .execute(db)
+                .await?;
+        }
+        if let Some(user) = &self.mentioned {
+            NewMention {
+                user: user.clone(),
+                message_id: Some(sent.id),
+                group_message_id: None,
+            }
+            .insert_into(mentions::table)
+            .execute(db)
+            .await?;
+        }
+        advance_heads(db, &sent, !self.blocked).await?;
+
+        Ok(sent)
+    }
+
+    /// Tells both participants about the `sent` message, once it is committed.
+    pub fn publish(&self, hub: &Hub, sent: DbMessage) {
+        let NewMessage {
+            sender, receiver, ..
+        } = &self.message;
+
+        if receiver != sender && !self.blocked {
+            hub.publish(receiver, Notification::NewMessage(sent.clone()));
+        }
+        hub.publish(sender, Notification::NewMessage(sent));
+        if self.mentioned.is_some() {
+            hub.publish(receiver, Notification::MentionsChanged);
+        }
+    }
+}
+
+pub async fn send_message(
+    State(Application {
+        db,
+        hub,
+        typing,
+        scheduler,
+        attachments: attachment_dir,
+        ..
+    }): State<Application>,
+    Path(SendMessagePath { peer }): Path<SendMessagePath>,
+    username: Username,
+    multipart: Multipart,
+) -> Result<(HxTrigger, AutoRefreshMessages), AppError> {
+    let SendMessageForm {
+        new_message_content,
+        last_seen_message_id,
+        changed_since,
+        reply_to,
+        attachments: mut uploads,
+        send_at,
+        timezone_offset,
+        ..
+    } = SendMessageForm::read(multipart).await?;
+    if new_message_content.trim().is_empty() && uploads.is_empty() {
+        return Err(AppError::Invalid("Messages can't be empty."));
+    }
+    if send_at.is_some() && !uploads.is_empty() {
+        return Err(AppError::Invalid(
+            "Messages with attachments can't be scheduled.",
+        ));
+    }
+    if send_at.is_some_and(|send_at| send_at <= Utc::now().naive_utc()) {
+        return Err(AppError::Invalid(
+            "Messages can only be scheduled for the future.",
+        ));
+    }
+    for upload in &mut uploads {
+        upload.decode().await?;
+    }
+
+    let mut db = db.get().await?;
+
+    if let Some(id) = reply_to {
+        let original = DbMessage::find_between((&peer, &username), id)
+            .first(&mut db)
+            .await?;
+        if original.deleted_at.is_some() {
+            return Err(AppError::Invalid("Deleted messages can't be replied to."));
+        }
+    }
+
+    if let Some(send_at) = send_at {
+        User::named(&peer).first(&mut db).await?;
+
+        NewScheduledMessage {
+            sender: username.to_owned(),
+            receiver: peer.clone(),
+            content: new_message_content,
+            reply_to,
+            send_at,
+            timezone_offset,
+        }
+        .insert_into(scheduled_messages::table)
+        .execute(&mut db)
+        .await?;
+        scheduler.reschedule();
+    } else {
+        let outgoing = Outgoing::new(
+            &mut db,
+            NewMessage {
+                sender: username.to_owned(),
+                receiver: peer.clone(),
+                content: new_message_content,
+                reply_to,
+                notice: false,
+            },
+        )
+        .await?;
+        let sent = async {
+            for upload in &mut uploads {
+                upload.store(&attachment_dir).await?;
+            }
+            let sent = db
+                .transaction::<_, diesel::result::Error, _>(|db| {
+                    let (outgoing, uploads) = (&outgoing, &uploads);
+                    async move { outgoing.store(db, uploads).await }.scope_boxed()
+                })
+                .await?;
+            Ok::<_, AppError>(sent)
+        }
+        .await;
+        let sent = match sent {
+            Ok(sent) => sent,
+            Err(e) => {
+                // Files only the failed message would have used would be left behind otherwise.
+                for upload in &uploads {
+                    if let Err(e) = upload.discard(&mut db, &attachment_dir).await {
+                        e.log();
+                    }
+                }
+                return Err(e);
+            }
+        };
+        for upload in &mut uploads {
+            upload.generate_thumbnails(attachment_dir.clone());
+        }
+        outgoing.publish(&hub, sent);
+        if typing.stop(&username, &peer) && !outgoing.blocked {
+            hub.publish(&peer, Notification::Typing(username.to_owned()));
+        }
+    }
+
+    let new_messages = if let Some(last_seen_id) = last_seen_message_id {
+        DbMessage::after((&peer, &username), last_seen_id).load(db.as_mut())
+    } else {
+        DbMessage::between((&peer, &username)).load(db.as_mut())
+    }
+    .await?;
+
+    mark_read(&mut db, &hub, (&peer, &username), &new_messages).await?;
+    let changed =
+        changed_messages(&mut db, (&peer, &username), changed_since, &new_messages).await?;
+
+    Ok((
+        HxTrigger::NameOnly("new-message-in-active-conversation".into()),
+        AutoRefreshMessages::new(bubbles(&mut db, &username, new_messages).await?, &peer)
+            .with_changed(bubbles(&mut db, &username, changed).await?),
+    ))
+}
+
+/// Messages between `peers` edited, deleted or reacted to since the Unix timestamp `since` that
+/// aren't among the `new_messages` anyway.
+async fn changed_messages(
+    db: &mut AsyncMysqlConnection,
+    peers: (&str, &str),
+    since: Option<i64>,
+    new_messages: &[DbMessage],
+) -> QueryResult<Vec<DbMessage>> {
+    let Some(since) = since.and_then(|since| DateTime::from_timestamp(since, 0)) else {
+        return Ok(Vec::new());
+    };
+
+    let mut changed = DbMessage::changed_since(peers, since.naive_utc())
+        .load(db)
+        .await?;
+    changed.retain(|message| new_messages.iter().all(|new| new.id != message.id));
+
+    Ok(changed)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetNewMessagesPath {
+    peer: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetNewMessagesQuery {
+    #[serde(alias = "last-seen-message-id")]
+    last_seen_message_id: Option<u64>,
+    #[serde(alias = "changed-since")]
+    changed_since: Option<i64>,
+}
+
+pub async fn get_new_messages(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(GetNewMessagesPath { peer }): Path<GetNewMessagesPath>,
+    Query(GetNewMessagesQuery {
+        last_seen_message_id,
+        changed_since,
+    }): Query<GetNewMessagesQuery>,
+    username: Username,
+) -> Result<Either<(HxTrigger, AutoRefreshMessages), StatusCode>, AppError> {
+    let mut db = db.get().await?;
+
+    let new_messages = if let Some(last_seen_id) = last_seen_message_id {
+        DbMessage::after((&peer, &username), last_seen_id).load(db.as_mut())
+    } else {
+        DbMessage::between((&peer, &username)).load(db.as_mut())
+    }
+    .await?;
+
+    let changed =
+        changed_messages(&mut db, (&peer, &username), changed_since, &new_messages).await?;
+
+    if new_messages.is_empty() && changed.is_empty() {
+        return Ok(Either::E2(StatusCode::NO_CONTENT));
+    };
+
+    mark_read(&mut db, &hub, (&peer, &username), &new_messages).await?;
+
+    Ok(Either::E1((
+        HxTrigger::NameOnly("new-message-in-active-conversation".into()),
+        AutoRefreshMessages::new(bubbles(&mut db, &username, new_messages).await?, &peer)
+            .with_changed(bubbles(&mut db, &username, changed).await?),
+    )))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageEventsPath {
+    peer: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageEventsQuery {
+    #[serde(alias = "last-seen-message-id")]
+    last_seen_message_id: Option<u64>,
+}
+
+/// Pushes messages from `peer` as soon as they are sent, rendered the same way as the responses
+/// to [`get_new_messages`], which remains available for clients that lose the event stream.
+///
+/// The user's own messages are not pushed, since they are already part of the response to
+/// [`send_message`]. Once `peer` reads them, they are pushed again with their read receipts.
+pub async fn message_events(
+    State(Application {
+        db, hub, typing, ..
+    }): State<Application>,
+    Path(MessageEventsPath { peer }): Path<MessageEventsPath>,
+    Query(MessageEventsQuery {
+        last_seen_message_id,
+    }): Query<MessageEventsQuery>,
+    headers: HeaderMap,
+    username: Username,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    struct EventsState {
+        notifications: Receiver<Notification>,
+        last_seen_message_id: Option<u64>,
+    }
+
+    // Browsers that re-establish a dropped connection report the ID of the last event received.
+    let last_seen_message_id = headers
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok())
+        .or(last_seen_message_id);
+
+    let username = username.into_inner();
+
+    let stream = futures::stream::unfold(
+        EventsState {
+            notifications: hub.subscribe(&username),
+            last_seen_message_id,
+        },
+        move |mut state| {
+            let (db, hub, typing, username, peer) = (
+                db.clone(),
+                hub.clone(),
+                typing.clone(),
+                username.clone(),
+                peer.clone(),
+            );
+            let next = async move {
+                loop {
+                    match state.notifications.recv().await {
+                        Ok(Notification::NewMessage(message)) if message.sender == peer => {}
+                        Ok(Notification::MessageChanged(message))
+                            if message.is_between((&peer, &username)) =>
+                        {
+                            let mut db = db.get().await?;
+                            // Messages hidden from the user stay hidden whatever happens to them.
+                            let Some(message) =
+                                DbMessage::find_between((&peer, &username), message.id)
+                                    .first(&mut db)
+                                    .await
+                                    .optional()?
+                            else {
+                                continue;
+                            };
+
+                            let bubble = Message {
+                                oob: true,
+                                ..bubble(&mut db, &username, message).await?
+                            };
+
+                            let event = Event::default()
+                                .event("message-changed")
+                                .data(bubble.render()?);
+                            return Ok(Some((Ok(event), state)));
+                        }
+                        Ok(Notification::MessagesRead { reader, ids, .. }) if reader == peer => {
+                            let mut db = db.get().await?;
+                            let messages = DbMessage::with_ids(ids).load(&mut db).await?;
+
+                            let mut receipts = String::new();
+                            for message in bubbles(&mut db, &username, messages).await? {
+                                Message {
+                                    oob: true,
+                                    ..message
+                                }
+                                .render_into(&mut receipts)?;
+                            }
+
+                            let event = Event::default().event("messages-read").data(receipts);
+                            return Ok(Some((Ok(event), state)));
+                        }
+                        Ok(Notification::TimerChanged {
+                            setter,
+                            peer: other,
+                        }) if setter == peer || other == peer => {
+                            let timer =
+                                TimerControl::load(&mut *db.get().await?, (&peer, &username))
+                                    .await?;
+                            let event = Event::default().event("timer").data(timer.render()?);
+                            return Ok(Some((Ok(event), state)));
+                        }
+                        Ok(Notification::MessagesExpired(ids)) => {
+                            let mut removals = String::new();
+                            for id in ids {
+                                removals.push_str(&format!(
+                                    r#"<li id="message-{id}" hx-swap-oob="delete"></li>"#
+                                ));
+                            }
+
+                            let event = Event::default().event("messages-expired").data(removals);
+                            return Ok(Some((Ok(event), state)));
+                        }
+                        Ok(Notification::Typing(typist)) if typist == peer => {
+                            let event = Event::default()
+                                .event("typing")
+                                .data(TypingIndicator::new(&typing, (&peer, &username)).render()?);
+                            return Ok(Some((Ok(event), state)));
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return Ok(None),
+                    }
+
+                    let mut db = db.get().await?;
+                    let mut new_messages = if let Some(last_seen_id) = state.last_seen_message_id {
+                        DbMessage::after((&peer, &username), last_seen_id).load(&mut db)
+                    } else {
+                        DbMessage::between((&peer, &username)).load(&mut db)
+                    }
+                    .await?;
+
+                    new_messages.retain(|message| message.sender == peer);
+
+                    let Some(newest_id) = new_messages.as_slice().first().map(|message| message.id)
+                    else {
+                        continue;
+                    };
+                    state.last_seen_message_id = Some(newest_id);
+
+                    mark_read(&mut db, &hub, (&peer, &username), &new_messages).await?;
+
+                    let event = Event::default()
+                        .event("new-messages")
+                        .id(newest_id.to_string())
+                        .data(
+                            AutoRefreshMessages::new(
+                                bubbles(&mut db, &username, new_messages).await?,
+                                &peer,
+                            )
+                            .render()?,
+                        );
+
+                    return Ok(Some((Ok(event), state)));
+                }
+            };
+            async move { AppError::end_stream(next.await) }
+        },
+    );
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MarkConversationReadPath {
+    peer: String,
+}
+
+pub async fn mark_conversation_read(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(MarkConversationReadPath { peer }): Path<MarkConversationReadPath>,
+    username: Username,
+) -> Result<StatusCode, AppError> {
+    let mut db = db.get().await?;
+
+    let unread = DbMessage::unread((&peer, &username))
+        .load(db.as_mut())
+        .await?;
+
+    mark_read(&mut db, &hub, (&peer, &username), &unread).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TypingPath {
+    peer: String,
+}
+
+/// Lets the peer know that the user is typing, which it keeps being shown as for a few seconds
+/// after the last announcement.
+pub async fn announce_typing(
+    State(Application {
+        db, hub, typing, ..
+    }): State<Application>,
+    Path(TypingPath { peer }): Path<TypingPath>,
+    username: Username,
+) -> Result<StatusCode, AppError> {
+    if peer != username.as_str()
+        && typing.start(&username, &peer)
+        && Block::between(&peer, &username)
+            .first(&mut *db.get().await?)
+            .await
+            .optional()?
+            .is_none()
+    {
+        hub.publish(&peer, Notification::Typing(username.into_inner()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn typing_indicator(
+    State(Application { typing, .. }): State<Application>,
+    Path(TypingPath { peer }): Path<TypingPath>,
+    username: Username,
+) -> TypingIndicator {
+    TypingIndicator::new(&typing, (&peer, &username))
+}
+
+async fn presence_of(
+    db: &mut AsyncMysqlConnection,
+    presence: &Presence,
+    peer: &str,
+) -> QueryResult<PresenceDot> {
+    let user = UserPresence::named(peer).first(db).await.optional()?;
+
+    Ok(user
+        .map(|user| PresenceDot::new(presence, &user))
+        .unwrap_or_default())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PresencePath {
+    peer: String,
+}
+
+pub async fn peer_presence(
+    State(Application { db, presence, .. }): State<Application>,
+    Path(PresencePath { peer }): Path<PresencePath>,
+    _: Username,
+) -> Result<PresenceDot, AppError> {
+    Ok(presence_of(&mut *db.get().await?, &presence, &peer).await?)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeerPath {
+    peer: String,
+}
+
+/// Stops delivering messages from the peer and takes the conversation off the list, without the
+/// peer finding out.
+pub async fn block_peer(
+    State(Application { db, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
+    username: Username,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    if peer == username.as_str() {
+        return Err(AppError::Invalid("You can't block yourself."));
+    }
+
+    let mut db = db.get().await?;
+
+    User::named(&peer).first(&mut db).await?;
+
+    diesel::insert_or_ignore_into(blocks::table)
+        .values(NewBlock {
+            user: username.to_owned(),
+            peer: peer.clone(),
+        })
+        .execute(&mut db)
+        .await?;
+
+    Ok((
+        HxTrigger::NameOnly("conversations-changed".into()),
+        ConversationActions::load(&mut db, (&peer, &username)).await?,
+    ))
+}
+
+pub async fn unblock_peer(
+    State(Application { db, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
+    username: Username,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    let mut db = db.get().await?;
+
+    diesel::delete(blocks::table.find((username.as_str(), &peer)))
+        .execute(&mut db)
+        .await?;
 
-use crate::{
-    api::{login::Username, Application, Content, HtmxRequest, HxTrigger, Root},
-    model::{schema::messages::dsl, Message as DbMessage, NewMessage},
-};
+    Ok((
+        HxTrigger::NameOnly("conversations-changed".into()),
+        ConversationActions::load(&mut db, (&peer, &username)).await?,
+    ))
+}
 
-use super::MessagesPage;
+async fn set_muted(
+    db: &Pool<AsyncMysqlConnection>,
+    (peer, username): (&str, &str),
+    muted: bool,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    let mut db = db.get().await?;
 
-const MESSAGE_LIMIT: usize = 10;
+    diesel::update(conversation_heads::table.find((username, peer)))
+        .set(conversation_heads::muted.eq(muted))
+        .execute(&mut db)
+        .await?;
 
-pub fn router() -> Router<Application> {
-    Router::new()
-        .route("/:peer", get(get_conversation))
-        .route("/:peer", post(send_message))
-        .route("/:peer/poll", get(get_new_messages))
-        .route("/:peer/search", get(search))
-        .route("/:peer/:direction", get(load_more))
+    Ok((
+        HxTrigger::NameOnly("conversations-changed".into()),
+        ConversationActions::load(&mut db, (peer, username)).await?,
+    ))
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct GetConversation {
-    peer: String,
+/// Keeps the conversation in the list, but without counting unread messages or pushing updates
+/// for new ones.
+pub async fn mute_conversation(
+    State(Application { db, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
+    username: Username,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    set_muted(&db, (&peer, &username), true).await
 }
 
-#[derive(Template, Default)]
-#[template(path = "conversations/direct/hidden-refresh.html")]
-pub struct AutoRefreshMessages {
-    messages: Vec<Message>,
-    last_seen_message_id: Option<u64>,
-    peer: String,
+pub async fn unmute_conversation(
+    State(Application { db, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
+    username: Username,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    set_muted(&db, (&peer, &username), false).await
 }
 
-impl AutoRefreshMessages {
-    pub fn new<'a>(messages: Vec<DbMessage>, user: &str, peer: impl Into<Cow<'a, str>>) -> Self {
-        let last_seen_message_id = messages.as_slice().first().map(|message| message.id);
+/// Files the conversation away as `flags` for the user, a conversation being either pinned,
+/// archived or neither.
+async fn set_flags(
+    db: &Pool<AsyncMysqlConnection>,
+    (peer, username): (&str, &str),
+    flags: ConversationFlags,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    let mut db = db.get().await?;
 
-        Self {
-            peer: peer.into().into_owned(),
-            messages: messages
-                .into_iter()
-                .map(|msg| (msg.sender == user, msg).into())
-                .collect(),
-            last_seen_message_id,
+    ConversationHead::between(username, peer)
+        .first(&mut db)
+        .await?;
+
+    if flags.pinned {
+        let current = ConversationFlags::between(username, peer)
+            .first(&mut db)
+            .await
+            .optional()?
+            .unwrap_or_default();
+        let pinned: i64 = ConversationFlags::pinned_count(username)
+            .get_result(&mut db)
+            .await?;
+        if !current.pinned && pinned >= MAX_PINNED as i64 {
+            return Err(AppError::Invalid(
+                "Only up to 5 conversations can be pinned.",
+            ));
         }
     }
+
+    // Both flags are written at once, so that a concurrent change can't leave half of another.
+    diesel::replace_into(conversation_flags::table)
+        .values(NewConversationFlags {
+            user: username.to_owned(),
+            peer: peer.to_owned(),
+            pinned: flags.pinned,
+            archived: flags.archived,
+        })
+        .execute(&mut db)
+        .await?;
+
+    Ok((
+        HxTrigger::NameOnly("conversations-changed".into()),
+        ConversationActions::load(&mut db, (peer, username)).await?,
+    ))
 }
 
-#[derive(Template, Default)]
-#[template(path = "conversations/direct/conversation-details.html")]
-pub struct ConversationView {
-    messages: AutoRefreshMessages,
-    lazy_load: Option<LoadMore>,
+/// Keeps the conversation on top of the list, whichever way it is ordered.
+pub async fn pin_conversation(
+    State(Application { db, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
+    username: Username,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    let flags = ConversationFlags {
+        pinned: true,
+        archived: false,
+    };
+    set_flags(&db, (&peer, &username), flags).await
 }
 
-#[derive(Template, Debug, Clone)]
-#[template(path = "conversations/direct/individual-message.html")]
-pub struct Message {
-    yours: bool,
-    id: u64,
-    content: String,
-    date: String,
+pub async fn unpin_conversation(
+    State(Application { db, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
+    username: Username,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    set_flags(&db, (&peer, &username), ConversationFlags::default()).await
 }
 
-impl From<(bool, DbMessage)> for Message {
-    fn from((yours, msg): (bool, DbMessage)) -> Self {
-        Self {
-            yours,
-            id: msg.id,
-            content: msg.content,
-            date: msg.sent_at.to_string(),
-        }
-    }
+/// Takes the conversation off the list until the next message in it.
+pub async fn archive_conversation(
+    State(Application { db, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
+    username: Username,
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    let flags = ConversationFlags {
+        pinned: false,
+        archived: true,
+    };
+    set_flags(&db, (&peer, &username), flags).await
 }
 
-pub async fn get_conversation(
-    State(Application { db }): State<Application>,
-    htmx: Option<HtmxRequest>,
-    Path(GetConversation { peer }): Path<GetConversation>,
+pub async fn unarchive_conversation(
+    State(Application { db, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
     username: Username,
-) -> Either<Root, ConversationView> {
-    let messages_in_convo = DbMessage::limited((&peer, &username), MESSAGE_LIMIT)
-        .load(db.get().await.unwrap().as_mut())
-        .await
-        .unwrap();
+) -> Result<(HxTrigger, ConversationActions), AppError> {
+    set_flags(&db, (&peer, &username), ConversationFlags::default()).await
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TimerForm {
+    length: TimerLength,
+    from_read: Option<String>,
+}
 
-    let lazy_load = LoadMore::new(LoadDirection::Earlier, &messages_in_convo, peer.clone());
+/// Sets how long messages between the user and the peer last from now on, leaving a notice of
+/// the change in the conversation.
+pub async fn set_timer(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(PeerPath { peer }): Path<PeerPath>,
+    username: Username,
+    Form(TimerForm { length, from_read }): Form<TimerForm>,
+) -> Result<TimerControl, AppError> {
+    let mut db = db.get().await?;
 
-    let conversation = ConversationView {
-        messages: AutoRefreshMessages::new(messages_in_convo, &username, &peer),
-        lazy_load,
+    User::named(&peer).first(&mut db).await?;
+
+    let current = DisappearingTimer::between((&peer, &username))
+        .first(&mut db)
+        .await
+        .optional()?
+        .map(|timer| (timer.duration_secs, timer.from_read));
+    let wanted = length.secs().map(|secs| (secs, from_read.is_some()));
+    if current == wanted {
+        return Ok(TimerControl::load(&mut db, (&peer, &username)).await?);
+    }
+
+    let content = match wanted {
+        Some((secs, from_read)) => format!(
+            "{} set messages to disappear {} after they are {}.",
+            username.as_str(),
+            describe_secs(secs),
+            if from_read { "read" } else { "sent" }
+        ),
+        None => format!("{} turned off disappearing messages.", username.as_str()),
     };
+    let outgoing = Outgoing::new(
+        &mut db,
+        NewMessage {
+            sender: username.to_owned(),
+            receiver: peer.clone(),
+            content,
+            reply_to: None,
+            notice: true,
+        },
+    )
+    .await?;
+    // The timer applies to the peer's messages as well, which a blocked user has no say over.
+    if outgoing.blocked {
+        return Err(AppError::Forbidden);
+    }
 
-    if let None | Some(HtmxRequest { restore: true, .. }) = htmx {
-        Either::E1(Root {
-            content: Content::Messages(MessagesPage {
-                selected: Some(peer),
-            }),
+    let sent = db
+        .transaction::<_, diesel::result::Error, _>(|db| {
+            let (outgoing, peer, username) = (&outgoing, &peer, &username);
+            async move {
+                match wanted {
+                    Some((secs, from_read)) => {
+                        diesel::replace_into(disappearing_timers::table)
+                            .values(NewDisappearingTimer::new(
+                                (peer, username),
+                                secs,
+                                from_read,
+                                username,
+                            ))
+                            .execute(db)
+                            .await?;
+                    }
+                    None => {
+                        diesel::delete(DisappearingTimer::of((peer, username)))
+                            .execute(db)
+                            .await?;
+                    }
+                }
+
+                outgoing.store(db, &[]).await
+            }
+            .scope_boxed()
         })
-    } else {
-        Either::E2(conversation)
+        .await?;
+    outgoing.publish(&hub, sent);
+
+    let notification = Notification::TimerChanged {
+        setter: username.to_owned(),
+        peer: peer.clone(),
+    };
+    if peer != username.as_str() {
+        hub.publish(&peer, notification.clone());
     }
+    hub.publish(&username, notification);
+
+    Ok(TimerControl::load(&mut db, (&peer, &username)).await?)
 }
 
 #[derive(Debug, Clone, Deserialize)]
-pub struct SendMessagePath {
+pub struct MessagePath {
     peer: String,
+    id: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
-pub struct SendMessageForm {
-    #[serde(rename = "new-message-content")]
-    new_message_content: String,
-    #[serde(rename = "last-seen-message-id")]
-    last_seen_message_id: Option<u64>,
+pub struct EditMessageForm {
+    content: String,
 }
 
-pub async fn send_message(
-    State(Application { db, .. }): State<Application>,
-    Path(SendMessagePath { peer }): Path<SendMessagePath>,
+/// Replaces the content of one of the user's own messages, keeping the previous version around.
+pub async fn edit_message(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(MessagePath { peer, id }): Path<MessagePath>,
     username: Username,
-    Form(SendMessageForm {
-        new_message_content,
-        last_seen_message_id,
-    }): Form<SendMessageForm>,
-) -> Result<(HxTrigger, AutoRefreshMessages), StatusCode> {
-    NewMessage {
-        sender: username.to_owned(),
-        receiver: peer.clone(),
-        content: new_message_content.clone(),
-    }
-    .insert_into(dsl::messages)
-    .execute(db.get().await.unwrap().borrow_mut())
-    .await
-    .unwrap();
-
-    let mut db = db.get().await.unwrap();
-    let new_messages = if let Some(last_seen_id) = last_seen_message_id {
-        DbMessage::after((&peer, &username), last_seen_id).load(db.as_mut())
+    Form(EditMessageForm { content }): Form<EditMessageForm>,
+) -> Result<Message, AppError> {
+    let content = content.trim().to_owned();
+    if content.is_empty() {
+        return Err(AppError::Invalid("Messages can't be empty."));
+    }
+
+    let mut db = db.get().await?;
+
+    let message = DbMessage::find_between((&peer, &username), id)
+        .first(&mut db)
+        .await?;
+    if message.sender != username.as_str() {
+        return Err(AppError::Forbidden);
+    }
+    if message.notice {
+        return Err(AppError::Invalid("Notices can't be edited."));
+    }
+    if message.deleted_at.is_some() {
+        return Err(AppError::Invalid("Deleted messages can't be edited."));
+    }
+    if message.content == content {
+        return Ok(bubble(&mut db, &username, message).await?);
+    }
+
+    // Mentions follow the new content, unless the message is hidden from the peer.
+    let visible = DbMessage::find_between((&username, &peer), id)
+        .first(&mut db)
+        .await
+        .optional()?
+        .is_some();
+    let mentioned = if visible {
+        mentioned_receiver(&mut db, (&username, &peer), &content).await?
     } else {
-        DbMessage::between((&peer, &username)).load(db.as_mut())
+        None
+    };
+
+    let edited_at = Utc::now().naive_utc();
+    let previous = NewMessageEdit {
+        message_id: id,
+        content: message.content.clone(),
+        edited_at,
+    };
+    let new_content = content.clone();
+    let mentions_changed = db
+        .transaction::<_, diesel::result::Error, _>(|db| {
+            async move {
+                previous
+                    .insert_into(message_edits::table)
+                    .execute(db)
+                    .await?;
+                diesel::update(dsl::messages.find(id))
+                    .set((dsl::content.eq(new_content), dsl::edited_at.eq(edited_at)))
+                    .execute(db)
+                    .await?;
+
+                let mention = mentions::table
+                    .filter(mentions::message_id.eq(id))
+                    .select(mentions::id)
+                    .first::<u64>(db)
+                    .await
+                    .optional()?;
+                match (mentioned, mention) {
+                    (Some(user), None) => {
+                        NewMention {
+                            user,
+                            message_id: Some(id),
+                            group_message_id: None,
+                        }
+                        .insert_into(mentions::table)
+                        .execute(db)
+                        .await?;
+                        Ok(true)
+                    }
+                    (None, Some(mention)) => {
+                        diesel::delete(mentions::table.find(mention))
+                            .execute(db)
+                            .await?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    let edited = DbMessage {
+        content,
+        edited_at: Some(edited_at),
+        ..message
+    };
+    if peer != username.as_str() {
+        hub.publish(&peer, Notification::MessageChanged(edited.clone()));
+    }
+    hub.publish(&username, Notification::MessageChanged(edited.clone()));
+    if mentions_changed {
+        hub.publish(&peer, Notification::MentionsChanged);
     }
-    .await
-    .unwrap();
 
-    Ok((
-        HxTrigger::NameOnly("new-message-in-active-conversation".into()),
-        AutoRefreshMessages::new(new_messages, &username, &peer),
-    ))
+    Ok(bubble(&mut db, &username, edited).await?)
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct GetNewMessagesPath {
-    peer: String,
+pub struct Revision {
+    content: String,
+    replaced_at: String,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct GetNewMessagesQuery {
-    #[serde(alias = "last-seen-message-id")]
-    last_seen_message_id: Option<u64>,
+#[derive(Template)]
+#[template(path = "conversations/direct/message-history.html")]
+pub struct MessageHistory {
+    revisions: Vec<Revision>,
 }
 
-pub async fn get_new_messages(
-    State(Application { db }): State<Application>,
-    Path(GetNewMessagesPath { peer }): Path<GetNewMessagesPath>,
-    Query(GetNewMessagesQuery {
-        last_seen_message_id,
-    }): Query<GetNewMessagesQuery>,
+/// The previous versions of a message, for both participants of the conversation.
+pub async fn message_history(
+    State(Application { db, .. }): State<Application>,
+    Path(MessagePath { peer, id }): Path<MessagePath>,
     username: Username,
-) -> Result<(HxTrigger, AutoRefreshMessages), StatusCode> {
-    let mut db = db.get().await.unwrap();
+) -> Result<MessageHistory, AppError> {
+    let mut db = db.get().await?;
 
-    let new_messages = if let Some(last_seen_id) = last_seen_message_id {
-        DbMessage::after((&peer, &username), last_seen_id).load(db.as_mut())
-    } else {
-        DbMessage::between((&peer, &username)).load(db.as_mut())
+    let message = DbMessage::find_between((&peer, &username), id)
+        .first(&mut db)
+        .await?;
+    if message.deleted_at.is_some() {
+        return Err(AppError::NotFound);
+    }
+
+    let revisions = MessageEdit::of_message(id)
+        .load(&mut db)
+        .await?
+        .into_iter()
+        .map(|edit| Revision {
+            content: edit.content,
+            replaced_at: edit.edited_at.to_string(),
+        })
+        .collect();
+
+    Ok(MessageHistory { revisions })
+}
+
+/// Deletes one of the user's own messages for both participants, leaving a tombstone in its place.
+pub async fn delete_message(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(MessagePath { peer, id }): Path<MessagePath>,
+    username: Username,
+) -> Result<Message, AppError> {
+    let mut db = db.get().await?;
+
+    let message = DbMessage::find_between((&peer, &username), id)
+        .first(&mut db)
+        .await?;
+    if message.sender != username.as_str() {
+        return Err(AppError::Forbidden);
+    }
+    if message.deleted_at.is_some() {
+        return Ok(bubble(&mut db, &username, message).await?);
+    }
+
+    let deleted_at = Utc::now().naive_utc();
+    diesel::update(dsl::messages.find(id))
+        .set((
+            dsl::deleted_at.eq(deleted_at),
+            dsl::deleted_by.eq(username.as_str()),
+        ))
+        .execute(&mut db)
+        .await?;
+    let unmentioned = diesel::delete(mentions::table.filter(mentions::message_id.eq(id)))
+        .execute(&mut db)
+        .await?;
+    if unmentioned > 0 {
+        hub.publish(&peer, Notification::MentionsChanged);
     }
-    .await
-    .unwrap();
 
-    if new_messages.is_empty() {
-        return Err(StatusCode::NO_CONTENT);
+    let deleted = DbMessage {
+        deleted_at: Some(deleted_at),
+        ..message
     };
+    if peer != username.as_str() {
+        hub.publish(&peer, Notification::MessageChanged(deleted.clone()));
+    }
+    hub.publish(&username, Notification::MessageChanged(deleted.clone()));
 
-    Ok((
-        HxTrigger::NameOnly("new-message-in-active-conversation".into()),
-        AutoRefreshMessages::new(new_messages, &username, &peer),
-    ))
+    Ok(bubble(&mut db, &username, deleted).await?)
+}
+
+/// Deletes a message for the user only, which removes its bubble, while the peer keeps seeing
+/// it.
+pub async fn hide_message(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(MessagePath { peer, id }): Path<MessagePath>,
+    username: Username,
+) -> Result<(), AppError> {
+    let mut db = db.get().await?;
+
+    let message = DbMessage::find_between((&peer, &username), id)
+        .first(&mut db)
+        .await?;
+
+    diesel::insert_or_ignore_into(hidden_messages::table)
+        .values(NewHiddenMessage {
+            user: username.to_string(),
+            message_id: id,
+        })
+        .execute(&mut db)
+        .await?;
+
+    // It won't show up anywhere to be read later on.
+    mark_read(&mut db, &hub, (&peer, &username), &[message]).await?;
+
+    Ok(())
+}
+
+#[derive(Template)]
+#[template(path = "conversations/direct/reply-target.html")]
+pub struct ReplyTarget {
+    quote: Quote,
+}
+
+/// Prepares the form for new messages to reply to a message.
+pub async fn reply_target(
+    State(Application { db, .. }): State<Application>,
+    Path(MessagePath { peer, id }): Path<MessagePath>,
+    username: Username,
+) -> Result<ReplyTarget, AppError> {
+    let mut db = db.get().await?;
+
+    let message = DbMessage::find_between((&peer, &username), id)
+        .first(&mut db)
+        .await?;
+    if message.deleted_at.is_some() {
+        return Err(AppError::Invalid("Deleted messages can't be replied to."));
+    }
+
+    Ok(ReplyTarget {
+        quote: Quote::new(message, &username),
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReactionForm {
+    emoji: String,
+}
+
+/// Adds the user's reaction with an emoji to a message, or takes it back if they already reacted
+/// with it.
+pub async fn toggle_reaction(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(MessagePath { peer, id }): Path<MessagePath>,
+    username: Username,
+    Form(ReactionForm { emoji }): Form<ReactionForm>,
+) -> Result<Message, AppError> {
+    if !REACTIONS.contains(&emoji.as_str()) {
+        return Err(AppError::Invalid("Messages can't be reacted to with this."));
+    }
+
+    let mut db = db.get().await?;
+
+    let message = DbMessage::find_between((&peer, &username), id)
+        .first(&mut db)
+        .await?;
+    if message.deleted_at.is_some() {
+        return Err(AppError::Invalid("Deleted messages can't be reacted to."));
+    }
+
+    let reacted_at = Utc::now().naive_utc();
+    let user = username.to_string();
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        async move {
+            let removed = diesel::delete(reactions::table.find((id, &user, &emoji)))
+                .execute(db)
+                .await?;
+            if removed == 0 {
+                NewReaction {
+                    message_id: id,
+                    user,
+                    emoji,
+                }
+                .insert_into(reactions::table)
+                .execute(db)
+                .await?;
+            }
+            diesel::update(dsl::messages.find(id))
+                .set(dsl::reacted_at.eq(reacted_at))
+                .execute(db)
+                .await?;
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    if peer != username.as_str() {
+        hub.publish(&peer, Notification::MessageChanged(message.clone()));
+    }
+    hub.publish(&username, Notification::MessageChanged(message.clone()));
+
+    Ok(bubble(&mut db, &username, message).await?)
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -226,23 +2101,28 @@ impl Display for LoadDirection {
 #[derive(Template, Debug, Clone)]
 #[template(path = "conversations/direct/load-more.html")]
 pub struct LoadMore {
-    peer: String,
+    url: String,
     id: u64,
     direction: LoadDirection,
 }
 
 impl LoadMore {
-    pub fn new(direction: LoadDirection, messages: &[DbMessage], peer: String) -> Option<Self> {
+    /// Continues after the last of a page of message `ids` (newest first), loading from
+    /// `{url}/{direction}`, unless the page wasn't full.
+    pub fn new(
+        direction: LoadDirection,
+        mut ids: impl DoubleEndedIterator<Item = u64> + ExactSizeIterator,
+        url: String,
+    ) -> Option<Self> {
+        if ids.len() != MESSAGE_LIMIT {
+            return None;
+        }
+
         match direction {
-            LoadDirection::Earlier => messages.last(),
-            LoadDirection::Later => messages.first(),
+            LoadDirection::Earlier => ids.next_back(),
+            LoadDirection::Later => ids.next(),
         }
-        .filter(|_| messages.len() == MESSAGE_LIMIT)
-        .map(|last_msg| Self {
-            peer: peer.clone(),
-            id: last_msg.id,
-            direction,
-        })
+        .map(|id| Self { url, id, direction })
     }
 }
 
@@ -264,49 +2144,76 @@ pub struct LazyLoaded {
     lazy_load: Option<LoadMore>,
 }
 
+impl LazyLoaded {
+    pub fn new(messages: Vec<Message>, lazy_load: Option<LoadMore>) -> Self {
+        Self {
+            messages,
+            lazy_load,
+        }
+    }
+}
+
 pub async fn load_more(
-    State(Application { db }): State<Application>,
+    State(Application { db, hub, .. }): State<Application>,
     Path(LoadMorePath { peer, direction }): Path<LoadMorePath>,
     Query(LoadMoreQuery { id }): Query<LoadMoreQuery>,
     username: Username,
-) -> LazyLoaded {
-    let mut db = db.get().await.unwrap();
+) -> Result<LazyLoaded, AppError> {
+    let mut db = db.get().await?;
 
     let messages = match direction {
         LoadDirection::Earlier => {
-            DbMessage::before_limited((&username, &peer), id, MESSAGE_LIMIT).load(&mut db)
+            DbMessage::before_limited((&peer, &username), id, MESSAGE_LIMIT).load(&mut db)
         }
         LoadDirection::Later => {
-            DbMessage::after_limited((&username, &peer), id, MESSAGE_LIMIT).load(&mut db)
+            DbMessage::after_limited((&peer, &username), id, MESSAGE_LIMIT).load(&mut db)
         }
     }
-    .await
-    .unwrap();
+    .await?;
 
-    let lazy_load = LoadMore::new(direction, &messages, peer.clone());
+    mark_read(&mut db, &hub, (&peer, &username), &messages).await?;
 
-    LazyLoaded {
-        messages: messages
-            .into_iter()
-            .map(|msg| (msg.sender.as_str() == username.as_str(), msg).into())
-            .collect(),
+    let lazy_load = LoadMore::new(
+        direction,
+        messages.iter().map(|msg| msg.id),
+        conversation_url(&peer),
+    );
+
+    Ok(LazyLoaded {
+        messages: bubbles(&mut db, &username, messages).await?,
         lazy_load,
-    }
+    })
 }
 
 #[derive(Debug)]
 pub enum SearchResultsInner {
     Found {
-        later: LoadMore,
-        message: Message,
-        earlier: LoadMore,
+        positioned: Positioned,
         result_id: u64,
+        position: MatchPosition,
         search_needle: String,
         peer: String,
     },
     NotFound,
 }
 
+/// Where a search result is among all matches, counting from the newest one.
+#[derive(Debug)]
+pub struct MatchPosition {
+    position: i64,
+    total: i64,
+}
+
+impl MatchPosition {
+    fn is_newest(&self) -> bool {
+        self.position <= 1
+    }
+
+    fn is_oldest(&self) -> bool {
+        self.position >= self.total
+    }
+}
+
 #[derive(Debug, Template)]
 #[template(path = "conversations/direct/search-results.html")]
 pub struct SearchResults {
@@ -318,6 +2225,8 @@ pub struct SearchResults {
 pub struct SearchQuery {
     search_needle: String,
     current_result: Option<u64>,
+    /// Where to look for the next match, relative to the current result.
+    direction: Option<LoadDirection>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -326,69 +2235,94 @@ pub struct SearchPath {
     peer: String,
 }
 
+/// Finds a message containing the search needle, starting at the newest one and then moving
+/// from the current result to the closest match in either direction.
 pub async fn search(
-    State(Application { db }): State<Application>,
+    State(Application { db, .. }): State<Application>,
     Path(SearchPath { peer }): Path<SearchPath>,
     Query(SearchQuery {
         search_needle,
         current_result,
+        direction,
     }): Query<SearchQuery>,
     username: Username,
-) -> Either<SearchResults, StatusCode> {
-    let mut db = db.get().await.unwrap();
+) -> Result<Either<SearchResults, StatusCode>, AppError> {
+    let mut db = db.get().await?;
+    let peers = (peer.as_str(), username.as_str());
 
-    let result = if let Some(current_result) = current_result {
-        // TODO: evaluate source to figure out direction
-        let next_message =
-            DbMessage::like_before((&peer, &username), &search_needle, current_result)
+    let result = match (current_result, direction) {
+        (Some(id), Some(LoadDirection::Later)) => {
+            DbMessage::match_after(peers, &search_needle, id)
                 .first(&mut db)
                 .await
-                .optional()
-                .unwrap();
-
-        let Some(next_message) = next_message else {
-            return Either::E2(StatusCode::NO_CONTENT);
-        };
-
-        next_message
-    } else {
-        // todo: evaluate source
-        let next_message = DbMessage::like((&peer, &username), &search_needle)
-            .first(&mut db)
-            .await
-            .optional()
-            .unwrap();
+        }
+        (Some(id), Some(LoadDirection::Earlier) | None) => {
+            DbMessage::match_before(peers, &search_needle, id)
+                .first(&mut db)
+                .await
+        }
+        (None, _) => {
+            DbMessage::newest_match(peers, &search_needle)
+                .first(&mut db)
+                .await
+        }
+    }
+    .optional()?;
 
-        let Some(next_message) = next_message else {
-            return Either::E1(SearchResults {
-                results: SearchResultsInner::NotFound,
-            });
-        };
+    let Some(result) = result else {
+        if current_result.is_some() {
+            return Ok(Either::E2(StatusCode::NO_CONTENT));
+        }
 
-        next_message
+        return Ok(Either::E1(SearchResults {
+            results: SearchResultsInner::NotFound,
+        }));
     };
 
-    let later = LoadMore {
-        peer: peer.clone(),
-        id: result.id,
-        direction: LoadDirection::Later,
-    };
-    let earlier = LoadMore {
-        peer: peer.clone(),
-        id: result.id,
-        direction: LoadDirection::Earlier,
-    };
+    let total = DbMessage::match_count(peers, &search_needle)
+        .get_result(&mut db)
+        .await?;
+    let position = DbMessage::matches_since(peers, &search_needle, result.id)
+        .get_result(&mut db)
+        .await?;
 
     let result_id = result.id;
+    let message = Message {
+        highlight: vec![search_needle.clone()],
+        ..bubble(&mut db, &username, result).await?
+    };
 
-    return Either::E1(SearchResults {
+    Ok(Either::E1(SearchResults {
         results: SearchResultsInner::Found {
-            later,
-            message: (result.sender == username.as_str(), result).into(),
-            earlier,
+            positioned: Positioned::new(message, conversation_url(&peer)),
             result_id,
+            position: MatchPosition { position, total },
             search_needle,
             peer,
         },
-    });
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe_secs, TimerLength};
+
+    #[test]
+    fn timer_lengths_are_described_in_whole_units() {
+        assert_eq!(describe_secs(60), "1 minute");
+        assert_eq!(describe_secs(90 * 60), "90 minutes");
+        assert_eq!(describe_secs(60 * 60), "1 hour");
+        assert_eq!(describe_secs(36 * 60 * 60), "36 hours");
+        assert_eq!(describe_secs(24 * 60 * 60), "1 day");
+        assert_eq!(describe_secs(7 * 24 * 60 * 60), "7 days");
+    }
+
+    #[test]
+    fn every_timer_length_has_a_description() {
+        for length in TimerLength::ALL {
+            if let Some(secs) = length.secs() {
+                assert!(!describe_secs(secs).starts_with('0'));
+            }
+        }
+    }
 }
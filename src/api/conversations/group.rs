@@ -0,0 +1,666 @@
+use std::convert::Infallible;
+
+use askama::Template;
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive},
+        Sse,
+    },
+    routing::{get, post, put},
+    Form, Router,
+};
+use axum_extra::either::Either;
+use diesel::{
+    ExpressionMethods, Insertable, NullableExpressionMethods, OptionalExtension, QueryDsl,
+    QueryResult,
+};
+use diesel_async::{
+    scoped_futures::ScopedFutureExt, AsyncConnection, AsyncMysqlConnection, RunQueryDsl,
+};
+use futures::Stream;
+use serde::Deserialize;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::{
+    api::{
+        login::Username, AppError, Application, Content, HtmxRequest, Hub, HxTrigger, Notification,
+        Root,
+    },
+    model::{
+        schema::{conversation_members, conversations, group_messages, mentions},
+        Conversation, GroupMessage, NewConversation, NewGroupMessage, NewMembership, NewMention,
+        User,
+    },
+};
+
+use super::{
+    direct::{AutoRefreshMessages, LazyLoaded, LoadDirection, LoadMore, MESSAGE_LIMIT},
+    markdown, Avatar, MessagesPage,
+};
+
+const MAX_TITLE_LENGTH: usize = 128;
+
+/// Group conversations only support sending plain messages and managing members, see
+/// [`GroupMessage`] for what they leave out.
+pub fn router() -> Router<Application> {
+    Router::new()
+        .route("/", post(create_group))
+        .route("/:id", get(get_group))
+        .route("/:id", post(send_message))
+        .route("/:id/poll", get(get_new_messages))
+        .route("/:id/events", get(group_events))
+        .route("/:id/title", put(rename_group))
+        .route("/:id/members", post(add_member))
+        .route("/:id/leave", post(leave_group))
+        .route("/:id/:direction", get(load_more))
+}
+
+pub fn group_url(id: u64) -> String {
+    format!("/conversations/group/{id}")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroupPath {
+    id: u64,
+}
+
+#[derive(Template)]
+#[template(path = "conversations/group/header.html")]
+pub struct GroupHeader {
+    id: u64,
+    title: String,
+    members: Vec<Avatar>,
+    error: Option<&'static str>,
+}
+
+impl GroupHeader {
+    async fn load(
+        db: &mut AsyncMysqlConnection,
+        conversation: Conversation,
+        error: Option<&'static str>,
+    ) -> QueryResult<Self> {
+        let members: Vec<String> = Conversation::members(conversation.id).load(db).await?;
+
+        Ok(Self {
+            id: conversation.id,
+            title: conversation.title,
+            members: members.into_iter().map(Avatar::new).collect(),
+            error,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "conversations/group/conversation-details.html")]
+pub struct GroupView {
+    header: GroupHeader,
+    messages: AutoRefreshMessages,
+    lazy_load: Option<LoadMore>,
+}
+
+/// The group conversation with the given ID, or `404 Not Found` if `username` isn't a member.
+async fn group_with_member(
+    db: &mut AsyncMysqlConnection,
+    id: u64,
+    username: &str,
+) -> Result<Conversation, AppError> {
+    Conversation::with_member(id, username)
+        .first(db)
+        .await
+        .optional()?
+        .ok_or(AppError::NotFound)
+}
+
+async fn notify_members(
+    db: &mut AsyncMysqlConnection,
+    hub: &Hub,
+    id: u64,
+    notification: Notification,
+) -> QueryResult<()> {
+    let members: Vec<String> = Conversation::members(id).load(db).await?;
+
+    for member in members {
+        hub.publish(&member, notification.clone());
+    }
+
+    Ok(())
+}
+
+fn auto_refresh(messages: Vec<GroupMessage>, user: &str, id: u64) -> AutoRefreshMessages {
+    let last_seen_message_id = messages.as_slice().first().map(|message| message.id);
+
+    AutoRefreshMessages::with_url(
+        messages
+            .into_iter()
+            .map(|msg| (msg.sender == user, msg).into())
+            .collect(),
+        last_seen_message_id,
+        group_url(id),
+    )
+}
+
+async fn group_view(
+    db: &mut AsyncMysqlConnection,
+    conversation: Conversation,
+    username: &str,
+) -> QueryResult<GroupView> {
+    let id = conversation.id;
+
+    let messages = GroupMessage::limited(id, MESSAGE_LIMIT).load(db).await?;
+
+    let lazy_load = LoadMore::new(
+        LoadDirection::Earlier,
+        messages.iter().map(|msg| msg.id),
+        group_url(id),
+    );
+
+    Ok(GroupView {
+        header: GroupHeader::load(db, conversation, None).await?,
+        messages: auto_refresh(messages, username, id),
+        lazy_load,
+    })
+}
+
+fn valid_title(title: &str) -> Option<String> {
+    let title = title.trim();
+    if title.is_empty() || title.chars().count() > MAX_TITLE_LENGTH {
+        return None;
+    }
+
+    Some(title.to_owned())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateGroupForm {
+    title: String,
+    /// Usernames separated by whitespace or commas; unknown users are skipped.
+    members: String,
+}
+
+pub async fn create_group(
+    State(Application { db, hub, .. }): State<Application>,
+    username: Username,
+    Form(CreateGroupForm { title, members }): Form<CreateGroupForm>,
+) -> Result<([(&'static str, String); 1], GroupView), AppError> {
+    let title = valid_title(&title).ok_or(AppError::Invalid(
+        "Group names need between 1 and 128 characters.",
+    ))?;
+
+    let mut invited: Vec<String> = members
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(Username::new)
+        .map(Username::into_inner)
+        .filter(|member| member != username.as_str())
+        .collect();
+    invited.sort();
+    invited.dedup();
+
+    let mut db = db.get().await?;
+
+    let mut members: Vec<String> = User::with_names(invited)
+        .load(&mut db)
+        .await?
+        .into_iter()
+        .map(|user| user.username)
+        .collect();
+    members.push(username.to_owned());
+
+    let new_conversation = NewConversation {
+        title,
+        created_by: username.to_owned(),
+    };
+    // A group without members would be left behind if adding them failed.
+    let conversation = db
+        .transaction::<_, diesel::result::Error, _>(|db| {
+            async move {
+                new_conversation
+                    .insert_into(conversations::table)
+                    .execute(db)
+                    .await?;
+
+                let conversation = Conversation::last_inserted().first(db).await?;
+
+                diesel::insert_into(conversation_members::table)
+                    .values(
+                        members
+                            .into_iter()
+                            .map(|member| NewMembership {
+                                conversation_id: conversation.id,
+                                member,
+                            })
+                            .collect::<Vec<_>>(),
+                    )
+                    .execute(db)
+                    .await?;
+
+                Ok(conversation)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    notify_members(
+        &mut db,
+        &hub,
+        conversation.id,
+        Notification::GroupChanged(conversation.id),
+    )
+    .await?;
+
+    Ok((
+        [("HX-Push-Url", group_url(conversation.id))],
+        group_view(&mut db, conversation, &username).await?,
+    ))
+}
+
+pub async fn get_group(
+    State(Application { db, .. }): State<Application>,
+    htmx: Option<HtmxRequest>,
+    Path(GroupPath { id }): Path<GroupPath>,
+    username: Username,
+) -> Result<Either<Root, GroupView>, AppError> {
+    if let None | Some(HtmxRequest { restore: true, .. }) = htmx {
+        return Ok(Either::E1(Root {
+            content: Content::Messages(MessagesPage {
+                user: username.into_inner(),
+                selected: Some(group_url(id)),
+            }),
+        }));
+    }
+
+    let mut db = db.get().await?;
+
+    let conversation = group_with_member(&mut db, id, &username).await?;
+
+    Ok(Either::E2(
+        group_view(&mut db, conversation, &username).await?,
+    ))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SendMessageForm {
+    #[serde(rename = "new-message-content")]
+    new_message_content: String,
+    #[serde(rename = "last-seen-message-id")]
+    last_seen_message_id: Option<u64>,
+}
+
+pub async fn send_message(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(GroupPath { id }): Path<GroupPath>,
+    username: Username,
+    Form(SendMessageForm {
+        new_message_content,
+        last_seen_message_id,
+    }): Form<SendMessageForm>,
+) -> Result<(HxTrigger, AutoRefreshMessages), AppError> {
+    let mut db = db.get().await?;
+
+    group_with_member(&mut db, id, &username).await?;
+
+    let members: Vec<String> = Conversation::members(id).load(&mut db).await?;
+    let mentioned: Vec<String> = markdown::mentioned(&new_message_content)
+        .into_iter()
+        .filter(|user| user != username.as_str() && members.contains(user))
+        .collect();
+
+    let new_message = NewGroupMessage {
+        conversation_id: id,
+        sender: username.to_owned(),
+        content: new_message_content,
+    };
+    let sent = db
+        .transaction::<_, diesel::result::Error, _>(|db| {
+            let mentioned = &mentioned;
+            async move {
+                new_message
+                    .insert_into(group_messages::table)
+                    .execute(db)
+                    .await?;
+                let sent = GroupMessage::last_inserted().first(db).await?;
+
+                diesel::update(conversations::table.find(id))
+                    .set((
+                        conversations::last_message_id.eq(sent.id),
+                        conversations::last_activity.eq(sent.sent_at),
+                    ))
+                    .execute(db)
+                    .await?;
+
+                if !mentioned.is_empty() {
+                    diesel::insert_into(mentions::table)
+                        .values(
+                            mentioned
+                                .iter()
+                                .map(|user| NewMention {
+                                    user: user.clone(),
+                                    message_id: None,
+                                    group_message_id: Some(sent.id),
+                                })
+                                .collect::<Vec<_>>(),
+                        )
+                        .execute(db)
+                        .await?;
+                }
+
+                Ok(sent)
+            }
+            .scope_boxed()
+        })
+        .await?;
+    notify_members(&mut db, &hub, id, Notification::NewGroupMessage(sent)).await?;
+    for user in &mentioned {
+        hub.publish(user, Notification::MentionsChanged);
+    }
+
+    let new_messages = if let Some(last_seen_id) = last_seen_message_id {
+        GroupMessage::after(id, last_seen_id).load(&mut db)
+    } else {
+        GroupMessage::in_conversation(id).load(&mut db)
+    }
+    .await?;
+
+    Ok((
+        HxTrigger::NameOnly("new-message-in-active-conversation".into()),
+        auto_refresh(new_messages, &username, id),
+    ))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetNewMessagesQuery {
+    #[serde(alias = "last-seen-message-id")]
+    last_seen_message_id: Option<u64>,
+}
+
+pub async fn get_new_messages(
+    State(Application { db, .. }): State<Application>,
+    Path(GroupPath { id }): Path<GroupPath>,
+    Query(GetNewMessagesQuery {
+        last_seen_message_id,
+    }): Query<GetNewMessagesQuery>,
+    username: Username,
+) -> Result<Either<(HxTrigger, AutoRefreshMessages), StatusCode>, AppError> {
+    let mut db = db.get().await?;
+
+    group_with_member(&mut db, id, &username).await?;
+
+    let new_messages = if let Some(last_seen_id) = last_seen_message_id {
+        GroupMessage::after(id, last_seen_id).load(&mut db)
+    } else {
+        GroupMessage::in_conversation(id).load(&mut db)
+    }
+    .await?;
+
+    if new_messages.is_empty() {
+        return Ok(Either::E2(StatusCode::NO_CONTENT));
+    };
+
+    Ok(Either::E1((
+        HxTrigger::NameOnly("new-message-in-active-conversation".into()),
+        auto_refresh(new_messages, &username, id),
+    )))
+}
+
+/// Pushes messages from other members as soon as they are sent, like
+/// [`super::direct::message_events`] does for conversations between two users, as well as the
+/// header whenever the title or the members change.
+///
+/// The stream ends once the user is no longer a member.
+pub async fn group_events(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(GroupPath { id }): Path<GroupPath>,
+    Query(GetNewMessagesQuery {
+        last_seen_message_id,
+    }): Query<GetNewMessagesQuery>,
+    headers: HeaderMap,
+    username: Username,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    struct EventsState {
+        notifications: Receiver<Notification>,
+        last_seen_message_id: Option<u64>,
+    }
+
+    group_with_member(&mut *db.get().await?, id, &username).await?;
+
+    let last_seen_message_id = headers
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok())
+        .or(last_seen_message_id);
+
+    let username = username.into_inner();
+
+    let stream = futures::stream::unfold(
+        EventsState {
+            notifications: hub.subscribe(&username),
+            last_seen_message_id,
+        },
+        move |mut state| {
+            let (db, username) = (db.clone(), username.clone());
+            let next = async move {
+                loop {
+                    match state.notifications.recv().await {
+                        Ok(Notification::NewGroupMessage(message))
+                            if message.conversation_id == id && message.sender != username => {}
+                        Ok(Notification::GroupChanged(changed)) if changed == id => {
+                            let mut db = db.get().await?;
+                            let conversation = Conversation::with_member(id, &username)
+                                .first(&mut db)
+                                .await
+                                .optional()?;
+                            let Some(conversation) = conversation else {
+                                return Ok(None);
+                            };
+
+                            let event = Event::default().event("group-changed").data(
+                                GroupHeader::load(&mut db, conversation, None)
+                                    .await?
+                                    .render()?,
+                            );
+                            return Ok(Some((Ok(event), state)));
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return Ok(None),
+                    }
+
+                    let mut db = db.get().await?;
+                    let mut new_messages = if let Some(last_seen_id) = state.last_seen_message_id {
+                        GroupMessage::after(id, last_seen_id).load(&mut db)
+                    } else {
+                        GroupMessage::in_conversation(id).load(&mut db)
+                    }
+                    .await?;
+
+                    new_messages.retain(|message| message.sender != username);
+
+                    let Some(newest_id) = new_messages.as_slice().first().map(|message| message.id)
+                    else {
+                        continue;
+                    };
+                    state.last_seen_message_id = Some(newest_id);
+
+                    let event = Event::default()
+                        .event("new-messages")
+                        .id(newest_id.to_string())
+                        .data(auto_refresh(new_messages, &username, id).render()?);
+
+                    return Ok(Some((Ok(event), state)));
+                }
+            };
+            async move { AppError::end_stream(next.await) }
+        },
+    );
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RenameGroupForm {
+    title: String,
+}
+
+pub async fn rename_group(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(GroupPath { id }): Path<GroupPath>,
+    username: Username,
+    Form(RenameGroupForm { title }): Form<RenameGroupForm>,
+) -> Result<GroupHeader, AppError> {
+    let mut db = db.get().await?;
+
+    let conversation = group_with_member(&mut db, id, &username).await?;
+
+    let Some(title) = valid_title(&title) else {
+        return Ok(GroupHeader::load(
+            &mut db,
+            conversation,
+            Some("Group names need between 1 and 128 characters."),
+        )
+        .await?);
+    };
+
+    diesel::update(conversations::table.find(id))
+        .set(conversations::title.eq(&title))
+        .execute(&mut db)
+        .await?;
+
+    notify_members(&mut db, &hub, id, Notification::GroupChanged(id)).await?;
+
+    Ok(GroupHeader::load(
+        &mut db,
+        Conversation {
+            title,
+            ..conversation
+        },
+        None,
+    )
+    .await?)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddMemberForm {
+    member: String,
+}
+
+pub async fn add_member(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(GroupPath { id }): Path<GroupPath>,
+    username: Username,
+    Form(AddMemberForm { member }): Form<AddMemberForm>,
+) -> Result<GroupHeader, AppError> {
+    let mut db = db.get().await?;
+
+    let conversation = group_with_member(&mut db, id, &username).await?;
+
+    let user = match Username::new(member.trim()) {
+        Some(member) => User::named(&member).first(&mut db).await.optional()?,
+        None => None,
+    };
+    let Some(user) = user else {
+        return Ok(GroupHeader::load(&mut db, conversation, Some("There is no such user.")).await?);
+    };
+
+    diesel::insert_or_ignore_into(conversation_members::table)
+        .values(NewMembership {
+            conversation_id: id,
+            member: user.username,
+        })
+        .execute(&mut db)
+        .await?;
+
+    notify_members(&mut db, &hub, id, Notification::GroupChanged(id)).await?;
+
+    Ok(GroupHeader::load(&mut db, conversation, None).await?)
+}
+
+/// Removes the user from the group, deleting the group along with its messages once the last
+/// member has left.
+pub async fn leave_group(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(GroupPath { id }): Path<GroupPath>,
+    username: Username,
+) -> Result<[(&'static str, &'static str); 1], AppError> {
+    let mut db = db.get().await?;
+
+    group_with_member(&mut db, id, &username).await?;
+
+    diesel::delete(conversation_members::table.find((id, username.as_str())))
+        .execute(&mut db)
+        .await?;
+    // Mentions in the group lead nowhere once the user can't see its messages anymore.
+    diesel::delete(
+        mentions::table
+            .filter(mentions::user.eq(username.as_str()))
+            .filter(
+                mentions::group_message_id.eq_any(
+                    group_messages::table
+                        .filter(group_messages::conversation_id.eq(id))
+                        .select(group_messages::id.nullable()),
+                ),
+            ),
+    )
+    .execute(&mut db)
+    .await?;
+
+    let remaining: Vec<String> = Conversation::members(id).load(&mut db).await?;
+    if remaining.is_empty() {
+        diesel::delete(conversations::table.find(id))
+            .execute(&mut db)
+            .await?;
+    } else {
+        notify_members(&mut db, &hub, id, Notification::GroupChanged(id)).await?;
+    }
+    hub.publish(&username, Notification::GroupChanged(id));
+
+    Ok([("HX-Redirect", "/conversations")])
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoadMorePath {
+    id: u64,
+    direction: LoadDirection,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoadMoreQuery {
+    id: u64,
+}
+
+pub async fn load_more(
+    State(Application { db, .. }): State<Application>,
+    Path(LoadMorePath {
+        id: conversation,
+        direction,
+    }): Path<LoadMorePath>,
+    Query(LoadMoreQuery { id }): Query<LoadMoreQuery>,
+    username: Username,
+) -> Result<LazyLoaded, AppError> {
+    let mut db = db.get().await?;
+
+    group_with_member(&mut db, conversation, &username).await?;
+
+    let messages = match direction {
+        LoadDirection::Earlier => {
+            GroupMessage::before_limited(conversation, id, MESSAGE_LIMIT).load(&mut db)
+        }
+        LoadDirection::Later => {
+            GroupMessage::after_limited(conversation, id, MESSAGE_LIMIT).load(&mut db)
+        }
+    }
+    .await?;
+
+    let lazy_load = LoadMore::new(
+        direction,
+        messages.iter().map(|msg| msg.id),
+        group_url(conversation),
+    );
+
+    Ok(LazyLoaded::new(
+        messages
+            .into_iter()
+            .map(|msg| (msg.sender == username.as_str(), msg).into())
+            .collect(),
+        lazy_load,
+    ))
+}
@@ -1,54 +1,147 @@
-use std::cmp::max;
+use std::{
+    cmp::{self, max},
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Display,
+};
+
+use chrono::NaiveDateTime;
 
 use askama::Template;
 use axum::{
     extract::{Path, Query, State},
     http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive},
+        Sse,
+    },
     routing::get,
     Router,
 };
-use diesel_async::RunQueryDsl;
+use axum_extra::either::Either;
+use diesel::{OptionalExtension, QueryResult};
+use diesel_async::{pooled_connection::deadpool::Pool, AsyncMysqlConnection, RunQueryDsl};
+use futures::Stream;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 
 use crate::{
-    api::{login::Username, Application},
-    model::Message as DbMessage,
+    api::{login::Username, AppError, Application, Notification, Presence},
+    model::{
+        containing, Conversation, ConversationHead, GroupMessage, Message as DbMessage, Shelf,
+        UserPresence,
+    },
 };
 
+use super::{group::group_url, markdown, Avatar, PresenceDot};
+
+/// Number of members shown next to a group in the list.
+const MAX_GROUP_AVATARS: usize = 3;
+
+/// Number of conversations loaded at once.
+const PAGE_SIZE: usize = 20;
+
+/// Number of conversations a user can pin, all of which go on top of the first page.
+pub const MAX_PINNED: usize = 5;
+
 pub fn router() -> Router<Application> {
-    Router::new().route("/:request-type", get(get_conversation_previews))
+    Router::new()
+        .route("/events", get(conversation_events))
+        .route("/more", get(get_more_conversations))
+        .route("/:request-type", get(get_conversation_previews))
 }
 
 #[derive(Debug, Clone)]
 struct ConversationPreview {
-    peer: String,
+    /// Value of the `selected-conversation` radio button: the peer, or `group-{id}` for groups.
+    key: String,
+    url: String,
+    title: String,
+    avatars: Vec<Avatar>,
+    /// Whether the peer is around, for conversations between two users.
+    presence: Option<PresenceDot>,
     date: String,
     preview: String,
     selected: bool,
+    pinned: bool,
+    unread: u32,
+    last_activity: NaiveDateTime,
 }
 
 impl ConversationPreview {
-    fn new(message: DbMessage, username: &str, selected: bool) -> Self {
+    /// Previews `message`, the newest one that is left of the conversation, if any.
+    fn new(head: ConversationHead, message: Option<DbMessage>, presence: PresenceDot) -> Self {
+        let peer = head.peer;
+
+        Self {
+            key: peer.clone(),
+            url: format!("/conversations/direct/{peer}"),
+            avatars: vec![Avatar::new(peer.clone())],
+            presence: Some(presence),
+            title: peer,
+            date: head.last_activity.to_string(),
+            preview: message
+                .map(|message| markdown::plain_text(&message.content))
+                .unwrap_or_default(),
+            selected: false,
+            pinned: false,
+            unread: if head.muted { 0 } else { head.unread_count },
+            last_activity: head.last_activity,
+        }
+    }
+
+    fn group(
+        conversation: Conversation,
+        message: Option<GroupMessage>,
+        members: Vec<String>,
+    ) -> Self {
+        let last_activity = conversation.last_activity;
+
         Self {
-            peer: if message.sender == username {
-                message.receiver
-            } else {
-                message.sender
-            },
-            date: message.sent_at.to_string(),
-            preview: message.content,
-            selected,
+            key: format!("group-{}", conversation.id),
+            url: group_url(conversation.id),
+            title: conversation.title,
+            avatars: members
+                .into_iter()
+                .take(MAX_GROUP_AVATARS)
+                .map(Avatar::new)
+                .collect(),
+            presence: None,
+            date: last_activity.to_string(),
+            preview: message
+                .map(|message| markdown::plain_text(&message.content))
+                .unwrap_or_default(),
+            selected: false,
+            pinned: false,
+            unread: 0,
+            last_activity,
         }
     }
 }
 
+#[derive(Template, Debug, Clone, Default)]
+#[template(path = "conversations/list/conversation-page.html")]
+pub struct ConversationPage {
+    conversations: Vec<ConversationPreview>,
+    next: Option<NextPage>,
+}
+
+/// Where the following page starts among conversations between two users and among groups.
+#[derive(Debug, Clone, Copy)]
+struct NextPage {
+    direct_offset: usize,
+    group_offset: usize,
+}
+
 #[derive(Template, Debug, Clone, Default)]
 #[template(path = "conversations/list/conversation-dynamic-bits.html")]
 pub struct ConversationItems {
-    conversations: Vec<ConversationPreview>,
+    page: ConversationPage,
     hidden_selected: Option<String>,
     start_new: Option<String>,
     last_seen_id: Option<u64>,
+    last_seen_group_message_id: Option<u64>,
+    events_query: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -65,104 +158,437 @@ pub enum Ordering {
     MostRecent,
 }
 
+impl Ordering {
+    fn compare(&self, left: &ConversationPreview, right: &ConversationPreview) -> cmp::Ordering {
+        match self {
+            // Roughly the case insensitive collation the database sorts by.
+            Ordering::Alphabetically => left.title.to_lowercase().cmp(&right.title.to_lowercase()),
+            Ordering::MostRecent => right.last_activity.cmp(&left.last_activity),
+        }
+    }
+}
+
+impl Display for Ordering {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Ordering::Alphabetically => "alphabetically",
+                Ordering::MostRecent => "most-recent",
+            }
+        )
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct GetConversationPreviewsQuery {
     last_seen_id: Option<u64>,
+    last_seen_group_message_id: Option<u64>,
     selected_conversation: Option<String>,
     search_needle: String,
     ordering: Ordering,
+    #[serde(default)]
+    direct_offset: usize,
+    #[serde(default)]
+    group_offset: usize,
+    /// Lists the archived conversations instead of the others.
+    #[serde(default)]
+    archived: bool,
 }
 
 pub async fn get_conversation_previews(
-    State(Application { db }): State<Application>,
+    State(Application { db, presence, .. }): State<Application>,
     Path(request_type): Path<RequestType>,
-    Query(GetConversationPreviewsQuery {
-        last_seen_id,
-        selected_conversation,
-        search_needle,
-        ordering,
-    }): Query<GetConversationPreviewsQuery>,
+    Query(query): Query<GetConversationPreviewsQuery>,
     username: Username,
-) -> Result<ConversationItems, StatusCode> {
-    let mut db = db.get().await.unwrap();
-
-    let mut most_recent_messages = DbMessage::most_recent(&username)
-        .load(&mut db)
-        .await
-        .unwrap();
-
-    // TODO: Include in query!
-    if !search_needle.is_empty() {
-        most_recent_messages.retain(|msg| {
-            (msg.sender == username.as_str() && msg.receiver.contains(&search_needle))
-                || (msg.receiver == username.as_str() && msg.sender.contains(&search_needle))
-        });
-    }
+) -> Result<Either<ConversationItems, StatusCode>, AppError> {
+    conversation_items(&db, &presence, request_type, query, &username).await
+}
 
-    let newest_id = most_recent_messages.as_slice().first().map(|msg| msg.id);
-    match (request_type, newest_id, last_seen_id) {
-        (RequestType::Poll, None, _) => return Err(StatusCode::NO_CONTENT),
-        (RequestType::Poll, Some(newest_id), Some(last_seen_id)) if last_seen_id >= newest_id => {
-            return Err(StatusCode::NO_CONTENT);
-        }
-        _ => {}
-    }
+pub async fn get_more_conversations(
+    State(Application { db, presence, .. }): State<Application>,
+    Query(query): Query<GetConversationPreviewsQuery>,
+    username: Username,
+) -> Result<ConversationPage, AppError> {
+    let mut db = db.get().await?;
+
+    Ok(conversation_page(&mut db, &presence, &query, &username).await?)
+}
 
-    // TODO: Include in query!
-    if let Ordering::Alphabetically = ordering {
-        most_recent_messages.sort_by(|left, right| {
-            macro_rules! get_peer {
-                ($id:ident) => {
-                    if $id.sender == username.as_str() {
-                        &$id.receiver
-                    } else {
-                        &$id.sender
+/// Pushes a freshly rendered conversation list whenever a message is sent to or by the user, or
+/// messages are read. Messages in muted conversations are left for the next poll instead.
+///
+/// The query is the same as for [`get_conversation_previews`], and is baked into the URL of the
+/// event stream by the rendered [`ConversationItems`], so that a new search reconnects.
+pub async fn conversation_events(
+    State(Application {
+        db, hub, presence, ..
+    }): State<Application>,
+    Query(query): Query<GetConversationPreviewsQuery>,
+    username: Username,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = futures::stream::unfold(hub.subscribe(&username), move |mut notifications| {
+        let (db, presence, query, username) = (
+            db.clone(),
+            presence.clone(),
+            query.clone(),
+            username.to_owned(),
+        );
+        let next = async move {
+            loop {
+                match notifications.recv().await {
+                    Ok(Notification::NewMessage(message)) if message.receiver == username => {
+                        let head = ConversationHead::between(&username, &message.sender)
+                            .first(&mut db.get().await?)
+                            .await
+                            .optional()?;
+                        if head.is_some_and(|head| head.muted) {
+                            continue;
+                        }
                     }
+                    Ok(
+                        Notification::NewMessage(_)
+                        | Notification::MessageChanged(_)
+                        | Notification::MessagesRead { .. }
+                        | Notification::NewGroupMessage(_)
+                        | Notification::GroupChanged(_)
+                        | Notification::MessagesExpired(_),
+                    )
+                    | Err(RecvError::Lagged(_)) => {}
+                    // Nothing the list shows changes with these.
+                    Ok(
+                        Notification::Typing(_)
+                        | Notification::MentionsChanged
+                        | Notification::TimerChanged { .. },
+                    ) => continue,
+                    Err(RecvError::Closed) => return Ok(None),
+                }
+
+                let Either::E1(items) = conversation_items(
+                    &db,
+                    &presence,
+                    RequestType::Search,
+                    query.clone(),
+                    &username,
+                )
+                .await?
+                else {
+                    continue;
                 };
+
+                return Ok(Some((
+                    Ok(Event::default()
+                        .event("conversations")
+                        .data(items.render()?)),
+                    notifications,
+                )));
             }
+        };
+        async move { AppError::end_stream(next.await) }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
 
-            get_peer!(left).cmp(get_peer!(right))
-        });
+/// Whether `newest_id` hasn't been seen by a client that last saw `last_seen_id`.
+fn is_unseen(newest_id: Option<u64>, last_seen_id: Option<u64>) -> bool {
+    match (newest_id, last_seen_id) {
+        (None, _) => false,
+        (Some(newest_id), Some(last_seen_id)) => newest_id > last_seen_id,
+        (Some(_), None) => true,
     }
+}
 
-    let last_seen_id = match (last_seen_id, newest_id) {
+fn newest(last_seen_id: Option<u64>, newest_id: Option<u64>) -> Option<u64> {
+    match (last_seen_id, newest_id) {
         (Some(last_seen_id), Some(newest_id)) => Some(max(last_seen_id, newest_id)),
         (id @ Some(_), None) | (None, id @ Some(_)) => id,
         (None, None) => None,
-    };
+    }
+}
 
-    let hidden_selected = selected_conversation.as_ref().and_then(|peer| {
-        if most_recent_messages
-            .iter()
-            .any(|msg| msg.is_between((peer, &username)))
-        {
-            None
-        } else {
-            Some(peer.to_owned())
+async fn conversation_items(
+    db: &Pool<AsyncMysqlConnection>,
+    presence: &Presence,
+    request_type: RequestType,
+    query: GetConversationPreviewsQuery,
+    username: &str,
+) -> Result<Either<ConversationItems, StatusCode>, AppError> {
+    let events_query = format!(
+        "search-needle={}&ordering={}{}{}",
+        urlencoding::encode(&query.search_needle),
+        query.ordering,
+        if query.archived { "&archived=true" } else { "" },
+        query
+            .selected_conversation
+            .as_ref()
+            .map(|key| format!("&selected-conversation={}", urlencoding::encode(key)))
+            .unwrap_or_default()
+    );
+
+    let mut db = db.get().await?;
+
+    let pattern = containing(&query.search_needle);
+
+    // Counting pinned and archived conversations in means those that were just unarchived by a
+    // new message are picked up too.
+    let newest_id = ConversationHead::newest_message_id(username, pattern.clone())
+        .get_result(&mut db)
+        .await?;
+    let newest_group_message_id = Conversation::newest_message_id(username, pattern)
+        .get_result(&mut db)
+        .await?;
+    if request_type == RequestType::Poll
+        && !is_unseen(newest_id, query.last_seen_id)
+        && !is_unseen(newest_group_message_id, query.last_seen_group_message_id)
+    {
+        return Ok(Either::E2(StatusCode::NO_CONTENT));
+    }
+
+    let mut start_new = Username::new(&query.search_needle);
+    if let Some(peer) = &start_new {
+        let existing = ConversationHead::between(username, peer)
+            .first(&mut db)
+            .await
+            .optional()?;
+        if existing.is_some() {
+            start_new = None;
         }
+    }
+
+    let page = conversation_page(&mut db, presence, &query, username).await?;
+
+    let hidden_selected = query.selected_conversation.filter(|key| {
+        !page
+            .conversations
+            .iter()
+            .any(|conversation| &conversation.key == key)
     });
 
-    let start_new = Username::new(&search_needle)
-        .filter(|peer| {
-            !most_recent_messages
-                .iter()
-                .any(|msg| msg.is_between((&username, &peer)))
-        })
-        .map(Username::into_inner);
+    Ok(Either::E1(ConversationItems {
+        page,
+        start_new: start_new.map(Username::into_inner),
+        hidden_selected,
+        last_seen_id: newest(query.last_seen_id, newest_id),
+        last_seen_group_message_id: newest(
+            query.last_seen_group_message_id,
+            newest_group_message_id,
+        ),
+        events_query,
+    }))
+}
+
+/// Loads a page of `username`'s conversations between two users, either those on `shelf` or
+/// those on neither.
+async fn load_heads(
+    db: &mut AsyncMysqlConnection,
+    username: &str,
+    pattern: String,
+    ordering: &Ordering,
+    shelf: Option<Shelf>,
+    offset: usize,
+    limit: usize,
+) -> QueryResult<Vec<ConversationHead>> {
+    match (ordering, shelf) {
+        (Ordering::MostRecent, None) => {
+            ConversationHead::most_recent(username, pattern, offset, limit)
+                .load(db)
+                .await
+        }
+        (Ordering::Alphabetically, None) => {
+            ConversationHead::alphabetically(username, pattern, offset, limit)
+                .load(db)
+                .await
+        }
+        (Ordering::MostRecent, Some(shelf)) => {
+            ConversationHead::most_recent_on_shelf(username, pattern, shelf, offset, limit)
+                .load(db)
+                .await
+        }
+        (Ordering::Alphabetically, Some(shelf)) => {
+            ConversationHead::alphabetically_on_shelf(username, pattern, shelf, offset, limit)
+                .load(db)
+                .await
+        }
+    }
+}
+
+/// Loads the page of conversations starting at the offsets in the `query`.
+///
+/// Conversations between two users and groups live in different tables, so a page worth of
+/// each is loaded and the two are merged, keeping track of how far into each of them the page
+/// reaches. Pinned conversations come first on the first page, and archived ones are only listed
+/// when asked for, without any groups.
+async fn conversation_page(
+    db: &mut AsyncMysqlConnection,
+    presence: &Presence,
+    query: &GetConversationPreviewsQuery,
+    username: &str,
+) -> QueryResult<ConversationPage> {
+    let pattern = containing(&query.search_needle);
+
+    let mut heads = if !query.archived && query.direct_offset == 0 && query.group_offset == 0 {
+        load_heads(
+            db,
+            username,
+            pattern.clone(),
+            &query.ordering,
+            Some(Shelf::Pinned),
+            0,
+            MAX_PINNED,
+        )
+        .await?
+    } else {
+        Vec::new()
+    };
+    let pinned = heads.len();
+    heads.extend(
+        load_heads(
+            db,
+            username,
+            pattern.clone(),
+            &query.ordering,
+            query.archived.then_some(Shelf::Archived),
+            query.direct_offset,
+            PAGE_SIZE,
+        )
+        .await?,
+    );
+
+    let groups = match query.ordering {
+        _ if query.archived => Ok(Vec::new()),
+        Ordering::MostRecent => {
+            Conversation::most_recent(username, pattern, query.group_offset, PAGE_SIZE)
+                .load(db)
+                .await
+        }
+        Ordering::Alphabetically => {
+            Conversation::alphabetically(username, pattern, query.group_offset, PAGE_SIZE)
+                .load(db)
+                .await
+        }
+    }?;
+
+    let mut members: HashMap<u64, Vec<String>> = HashMap::new();
+    for (conversation_id, member) in
+        Conversation::members_of(groups.iter().map(|group| group.id).collect())
+            .load::<(u64, String)>(db)
+            .await?
+    {
+        members.entry(conversation_id).or_default().push(member);
+    }
 
-    Ok(ConversationItems {
-        conversations: most_recent_messages
+    let mut last_messages: HashMap<u64, DbMessage> = DbMessage::remaining_with_ids(
+        username,
+        heads.iter().map(|head| head.last_message_id).collect(),
+    )
+    .load(db)
+    .await?
+    .into_iter()
+    .map(|message| (message.id, message))
+    .collect();
+
+    let presences: HashMap<String, UserPresence> =
+        UserPresence::with_names(heads.iter().map(|head| head.peer.clone()).collect())
+            .load(db)
+            .await?
+            .into_iter()
+            .map(|user| (user.username.clone(), user))
+            .collect();
+
+    // Conversations whose last message was deleted are previewed with the one before it instead.
+    let mut previews = Vec::with_capacity(heads.len());
+    for head in heads {
+        let message = match last_messages.remove(&head.last_message_id) {
+            Some(message) => Some(message),
+            None => DbMessage::newest_remaining((&head.peer, username))
+                .first(db)
+                .await
+                .optional()?,
+        };
+        let dot = presences
+            .get(&head.peer)
+            .map(|user| PresenceDot::new(presence, user))
+            .unwrap_or_default();
+        previews.push(ConversationPreview::new(head, message, dot));
+    }
+
+    let mut last_group_messages: HashMap<u64, GroupMessage> = GroupMessage::with_ids(
+        groups
+            .iter()
+            .filter_map(|group| group.last_message_id)
+            .collect(),
+    )
+    .load(db)
+    .await?
+    .into_iter()
+    .map(|message| (message.conversation_id, message))
+    .collect();
+
+    let listed = previews.split_off(pinned);
+    let mut conversations = previews;
+    for conversation in &mut conversations {
+        conversation.pinned = true;
+    }
+
+    let (merged, from_direct, from_groups) = merge(
+        listed,
+        groups
             .into_iter()
-            .map(|message| {
-                let selected = selected_conversation
-                    .as_ref()
-                    .is_some_and(|peer| message.is_between((peer, &username)));
-                ConversationPreview::new(message, username.as_str(), selected)
+            .map(|group| {
+                let message = last_group_messages.remove(&group.id);
+                let members = members.remove(&group.id).unwrap_or_default();
+                ConversationPreview::group(group, message, members)
             })
             .collect(),
-        start_new,
-        hidden_selected,
-        last_seen_id,
+        &query.ordering,
+    );
+    let next = (merged.len() == PAGE_SIZE).then_some(NextPage {
+        direct_offset: query.direct_offset + from_direct,
+        group_offset: query.group_offset + from_groups,
+    });
+    conversations.extend(merged);
+
+    for conversation in &mut conversations {
+        conversation.selected = query.selected_conversation.as_ref() == Some(&conversation.key);
+    }
+
+    Ok(ConversationPage {
+        conversations,
+        next,
     })
 }
+
+/// Merges conversations between two users and groups, both sorted by `ordering` already, into a
+/// single page, along with the number of conversations taken from either.
+fn merge(
+    direct: Vec<ConversationPreview>,
+    groups: Vec<ConversationPreview>,
+    ordering: &Ordering,
+) -> (Vec<ConversationPreview>, usize, usize) {
+    let mut direct = direct.into_iter().peekable();
+    let mut groups = groups.into_iter().peekable();
+    let (mut from_direct, mut from_groups) = (0, 0);
+
+    let mut merged = Vec::with_capacity(PAGE_SIZE);
+    while merged.len() < PAGE_SIZE {
+        let take_direct = match (direct.peek(), groups.peek()) {
+            (Some(left), Some(right)) => ordering.compare(left, right) != cmp::Ordering::Greater,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+
+        if take_direct {
+            merged.extend(direct.next());
+            from_direct += 1;
+        } else {
+            merged.extend(groups.next());
+            from_groups += 1;
+        }
+    }
+
+    (merged, from_direct, from_groups)
+}
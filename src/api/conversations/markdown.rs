@@ -0,0 +1,411 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::OnceLock,
+};
+
+use ammonia::{Builder, UrlRelative};
+use pulldown_cmark::{
+    escape::escape_html, html::push_html, CodeBlockKind, CowStr, Event, Parser, Tag,
+};
+use syntect::{
+    html::{ClassStyle, ClassedHTMLGenerator},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+use crate::api::login::Username;
+
+use super::direct::{conversation_url, split_matches};
+
+/// The only elements that survive in rendered messages, whatever the Markdown or raw HTML in
+/// them asked for.
+const ALLOWED_TAGS: [&str; 11] = [
+    "p", "br", "strong", "em", "code", "pre", "a", "ul", "ol", "li", "mark",
+];
+
+fn sanitizer() -> &'static Builder<'static> {
+    static SANITIZER: OnceLock<Builder<'static>> = OnceLock::new();
+
+    SANITIZER.get_or_init(|| {
+        let mut builder = Builder::empty();
+        builder
+            .tags(HashSet::from(ALLOWED_TAGS))
+            .tag_attributes(HashMap::from([
+                ("a", HashSet::from(["href"])),
+                ("ol", HashSet::from(["start"])),
+            ]))
+            .generic_attributes(HashSet::new())
+            .allowed_classes(HashMap::from([("a", HashSet::from(["mention"]))]))
+            .url_schemes(HashSet::from(["http", "https", "mailto"]))
+            .url_relative(UrlRelative::Custom(Box::new(mention_url)))
+            .link_rel(Some("noopener noreferrer nofollow"))
+            .set_tag_attribute_value("a", "target", "_blank");
+        builder
+    })
+}
+
+/// Keeps the relative links of mentions, and no others.
+fn mention_url(url: &str) -> Option<Cow<'_, str>> {
+    url.strip_prefix(&conversation_url(""))
+        .is_some_and(|peer| Username::new(peer).is_some())
+        .then_some(Cow::Borrowed(url))
+}
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// Code blocks longer than this are collapsed until expanded.
+const COLLAPSED_LINES: usize = 15;
+
+/// Renders the supported subset of Markdown in `content` to sanitized HTML, marking matches of
+/// any of the `highlight`ed search terms in its text and linking `@username` mentions to the
+/// conversation with that user.
+///
+/// Headings turn into plain paragraphs, images into links and block quotes into their content,
+/// while raw HTML is left to the sanitizer. Fenced code blocks with a language at the top level
+/// are highlighted, and skip the sanitizer since their HTML is generated from escaped text.
+pub fn render(content: &str, highlight: &[String]) -> String {
+    let mut html = String::new();
+    let mut segment = Vec::new();
+    let mut depth = 0;
+    let mut literal = 0;
+    let mut code: Option<(String, String)> = None;
+
+    for event in Parser::new(content) {
+        if let Some((language, text)) = &mut code {
+            match event {
+                Event::Text(part) => text.push_str(&part),
+                Event::End(_) => {
+                    html.push_str(&code_block(language, text));
+                    code = None;
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(language)))
+                if depth == 0 && !language.is_empty() =>
+            {
+                html.push_str(&sanitized(segment.drain(..)));
+                let language = language.split_whitespace().next().unwrap_or_default();
+                code = Some((language.to_owned(), String::new()));
+            }
+            event => {
+                match &event {
+                    Event::Start(tag) => {
+                        depth += 1;
+                        literal += usize::from(is_literal(tag));
+                    }
+                    Event::End(tag) => {
+                        depth -= 1;
+                        literal -= usize::from(is_literal(tag));
+                    }
+                    _ => {}
+                }
+                segment.extend(constrained(event, highlight, literal > 0));
+            }
+        }
+    }
+    html.push_str(&sanitized(segment.drain(..)));
+
+    html
+}
+
+/// Whether the text within `tag` is taken as it is, without looking for mentions in it.
+fn is_literal(tag: &Tag) -> bool {
+    matches!(tag, Tag::Link(..) | Tag::Image(..) | Tag::CodeBlock(_))
+}
+
+fn constrained<'a>(event: Event<'a>, highlight: &[String], literal: bool) -> Vec<Event<'a>> {
+    match event {
+        Event::Start(Tag::Heading(..)) => vec![Event::Start(Tag::Paragraph)],
+        Event::End(Tag::Heading(..)) => vec![Event::End(Tag::Paragraph)],
+        Event::Start(Tag::Image(link_type, url, title)) => {
+            vec![Event::Start(Tag::Link(link_type, url, title))]
+        }
+        Event::End(Tag::Image(link_type, url, title)) => {
+            vec![Event::End(Tag::Link(link_type, url, title))]
+        }
+        Event::Start(Tag::BlockQuote) | Event::End(Tag::BlockQuote) | Event::Rule => Vec::new(),
+        Event::Text(text) if !literal => with_mentions(&text, highlight),
+        Event::Text(text) if !highlight.is_empty() => highlighted(&text, highlight),
+        event => vec![event],
+    }
+}
+
+fn sanitized<'a>(events: impl Iterator<Item = Event<'a>>) -> String {
+    let mut html = String::new();
+    push_html(&mut html, events);
+
+    sanitizer().clean(&html).to_string()
+}
+
+/// A highlighted code block, with a button to copy it and collapsed if it is long.
+fn code_block(language: &str, code: &str) -> String {
+    let highlighted = highlighted_code(language, code).unwrap_or_else(|| escaped(code));
+    let pre = format!("<pre><code>{highlighted}</code></pre>");
+
+    let lines = code.lines().count();
+    let body = if lines > COLLAPSED_LINES {
+        format!(
+            "<details><summary>{lines} lines of {}</summary>{pre}</details>",
+            escaped(language)
+        )
+    } else {
+        pre
+    };
+
+    format!(
+        "<div class=\"code-block\">\
+            <button type=\"button\" class=\"copy-code\" hx-on:click=\"navigator.clipboard.writeText(this.parentElement.querySelector('code').textContent)\">Copy</button>\
+            {body}\
+        </div>"
+    )
+}
+
+/// `code` as HTML with `hl-` prefixed classes for the scopes of its tokens, if `language` is
+/// known.
+fn highlighted_code(language: &str, code: &str) -> Option<String> {
+    let syntaxes = syntaxes();
+    let syntax = syntaxes.find_syntax_by_token(language)?;
+
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(
+        syntax,
+        syntaxes,
+        ClassStyle::SpacedPrefixed { prefix: "hl-" },
+    );
+    for line in LinesWithEndings::from(code) {
+        generator
+            .parse_html_for_line_which_includes_newline(line)
+            .ok()?;
+    }
+
+    Some(generator.finalize())
+}
+
+fn escaped(text: &str) -> String {
+    let mut escaped = String::new();
+    // Writing to a `String` can't fail.
+    let _ = escape_html(&mut escaped, text);
+    escaped
+}
+
+fn highlighted(text: &str, highlight: &[String]) -> Vec<Event<'static>> {
+    split_matches(text, highlight)
+        .into_iter()
+        .flat_map(|(matched, part)| {
+            let part = Event::Text(CowStr::from(part.to_owned()));
+            if matched {
+                vec![
+                    Event::Html(CowStr::Borrowed("<mark>")),
+                    part,
+                    Event::Html(CowStr::Borrowed("</mark>")),
+                ]
+            } else {
+                vec![part]
+            }
+        })
+        .collect()
+}
+
+fn with_mentions(text: &str, highlight: &[String]) -> Vec<Event<'static>> {
+    split_mentions(text)
+        .into_iter()
+        .flat_map(|(mentioned, part)| {
+            let part = if highlight.is_empty() {
+                vec![Event::Text(CowStr::from(part.to_owned()))]
+            } else {
+                highlighted(part, highlight)
+            };
+            match mentioned {
+                Some(username) => {
+                    let link = format!(
+                        "<a class=\"mention\" href=\"{}\">",
+                        conversation_url(username.as_str())
+                    );
+                    let mut events = vec![Event::Html(CowStr::from(link))];
+                    events.extend(part);
+                    events.push(Event::Html(CowStr::Borrowed("</a>")));
+                    events
+                }
+                None => part,
+            }
+        })
+        .collect()
+}
+
+/// Splits `text` into `@username` mentions, along with the user, and the text between them.
+fn split_mentions(text: &str) -> Vec<(Option<Username>, &str)> {
+    let mut parts = Vec::new();
+    let mut rest = 0;
+    let mut search = 0;
+
+    while let Some(offset) = text[search..].find('@') {
+        let at = search + offset;
+        let end = text[at + 1..]
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .map_or(text.len(), |length| at + 1 + length);
+        search = at + 1;
+
+        // An `@` right after a word, like in e-mail addresses, doesn't start a mention.
+        if text[..at]
+            .chars()
+            .next_back()
+            .is_some_and(char::is_alphanumeric)
+        {
+            continue;
+        }
+        let Some(username) = Username::new(&text[at + 1..end]) else {
+            continue;
+        };
+
+        if rest < at {
+            parts.push((None, &text[rest..at]));
+        }
+        parts.push((Some(username), &text[at..end]));
+        rest = end;
+        search = end;
+    }
+    if rest < text.len() {
+        parts.push((None, &text[rest..]));
+    }
+
+    parts
+}
+
+/// The users mentioned in `content`, each once, leaving out anything in code or links just like
+/// [`render`] does.
+pub fn mentioned(content: &str) -> Vec<String> {
+    let mut mentioned = Vec::new();
+    let mut literal = 0;
+    for event in Parser::new(content) {
+        match event {
+            Event::Start(tag) => literal += usize::from(is_literal(&tag)),
+            Event::End(tag) => literal -= usize::from(is_literal(&tag)),
+            Event::Text(text) if literal == 0 => mentioned.extend(
+                split_mentions(&text)
+                    .into_iter()
+                    .filter_map(|(username, _)| username.map(Username::into_inner)),
+            ),
+            _ => {}
+        }
+    }
+
+    mentioned.sort();
+    mentioned.dedup();
+    mentioned
+}
+
+/// The text of `content` without any of its formatting, on a single line.
+pub fn plain_text(content: &str) -> String {
+    let mut text = String::new();
+    for event in Parser::new(content) {
+        match event {
+            Event::Text(part) | Event::Code(part) => text.push_str(&part),
+            Event::SoftBreak
+            | Event::HardBreak
+            | Event::End(
+                Tag::Paragraph | Tag::Heading(..) | Tag::BlockQuote | Tag::CodeBlock(_) | Tag::Item,
+            ) => text.push(' '),
+            _ => {}
+        }
+    }
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mentioned, plain_text, render};
+
+    #[test]
+    fn raw_html_is_sanitized() {
+        let html = render(
+            "<script>alert(1)</script><img src=x onerror=alert(1)><b onclick=\"x()\">bold</b>",
+            &[],
+        );
+        assert!(!html.contains("<script"), "{html}");
+        assert!(!html.contains("<img"), "{html}");
+        assert!(!html.contains("onclick"), "{html}");
+        assert!(!html.contains("onerror"), "{html}");
+    }
+
+    #[test]
+    fn javascript_links_lose_their_target() {
+        for content in [
+            "[click](javascript:alert(1))",
+            "<a href=\"javascript:alert(1)\">click</a>",
+            "![image](javascript:alert(1))",
+        ] {
+            let html = render(content, &[]);
+            assert!(!html.contains("javascript:"), "{html}");
+        }
+    }
+
+    #[test]
+    fn web_links_open_in_a_new_tab() {
+        let html = render("[docs](https://example.com)", &[]);
+        assert!(html.contains("href=\"https://example.com\""), "{html}");
+        assert!(html.contains("target=\"_blank\""), "{html}");
+        assert!(html.contains("noopener"), "{html}");
+    }
+
+    #[test]
+    fn relative_links_are_dropped() {
+        let html = render("[settings](/sessions)", &[]);
+        assert!(!html.contains("href"), "{html}");
+    }
+
+    #[test]
+    fn headings_become_paragraphs() {
+        assert_eq!(render("# Title", &[]), "<p>Title</p>\n");
+    }
+
+    #[test]
+    fn search_terms_are_highlighted() {
+        let html = render("Hello World", &["world".to_owned()]);
+        assert!(html.contains("<mark>World</mark>"), "{html}");
+    }
+
+    #[test]
+    fn plain_text_drops_formatting() {
+        assert_eq!(
+            plain_text("# Title\n\nSome **bold** and `code`.\n\n- one\n- two"),
+            "Title Some bold and code. one two"
+        );
+    }
+
+    #[test]
+    fn mentions_link_to_the_conversation() {
+        let html = render("hi @alice!", &[]);
+        assert!(
+            html.contains("<a class=\"mention\" href=\"/conversations/direct/alice\""),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn mentions_are_collected_once() {
+        assert_eq!(mentioned("@bob and @alice, @bob again"), ["alice", "bob"]);
+    }
+
+    #[test]
+    fn mentions_need_a_valid_username_on_its_own() {
+        assert!(mentioned("mail me at bob@example.com").is_empty());
+        assert!(mentioned("@1bob @ @_x").is_empty());
+    }
+
+    #[test]
+    fn mentions_in_code_and_links_are_left_out() {
+        assert!(mentioned("`@alice` and [@bob](https://example.com)").is_empty());
+        assert!(mentioned("```\n@carol\n```").is_empty());
+        let html = render("`@alice`", &[]);
+        assert!(!html.contains("mention"), "{html}");
+    }
+}
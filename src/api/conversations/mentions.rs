@@ -0,0 +1,277 @@
+use std::{collections::HashMap, convert::Infallible};
+
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    response::{
+        sse::{Event, KeepAlive},
+        Redirect, Sse,
+    },
+    routing::{get, post},
+    Router,
+};
+use axum_extra::either::Either;
+use diesel::{dsl::now, ExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::{AsyncMysqlConnection, RunQueryDsl};
+use futures::Stream;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    api::{login::Username, AppError, Application, Content, HtmxRequest, Notification, Root},
+    model::{schema::mentions, Conversation, GroupMessage, Mention, Message as DbMessage},
+};
+
+use super::{direct::conversation_url, group::group_url, markdown, Avatar};
+
+/// Number of mentions shown at most, newest first.
+const MENTION_LIMIT: usize = 50;
+
+pub fn router() -> Router<Application> {
+    Router::new()
+        .route("/", get(get_mentions))
+        .route("/read", post(mark_all_read))
+        .route("/badge", get(badge))
+        .route("/events", get(badge_events))
+        .route("/:id", get(open_mention))
+}
+
+#[derive(Template)]
+#[template(path = "conversations/mentions/index.html")]
+pub struct MentionsPage {
+    user: String,
+    mentions: MentionList,
+}
+
+#[derive(Template)]
+#[template(path = "conversations/mentions/list.html")]
+pub struct MentionList {
+    mentions: Vec<MentionEntry>,
+}
+
+/// A message mentioning the user, leading to it through [`open_mention`].
+pub struct MentionEntry {
+    id: u64,
+    author: Avatar,
+    /// Where the message was sent, for group messages.
+    group: Option<String>,
+    date: String,
+    excerpt: String,
+    unread: bool,
+}
+
+/// The number of unread mentions, kept up to date over an event stream.
+#[derive(Template)]
+#[template(path = "conversations/mentions/badge.html")]
+pub struct MentionsBadge {
+    unread: i64,
+}
+
+impl MentionList {
+    /// The newest mentions of `user` in messages they can still see.
+    async fn load(db: &mut AsyncMysqlConnection, user: &str) -> QueryResult<Self> {
+        let mentions = Mention::newest(user, MENTION_LIMIT).load(db).await?;
+
+        let direct: HashMap<u64, DbMessage> = DbMessage::remaining_with_ids(
+            user,
+            mentions
+                .iter()
+                .filter_map(|mention| mention.message_id)
+                .collect(),
+        )
+        .load(db)
+        .await?
+        .into_iter()
+        .map(|message| (message.id, message))
+        .collect();
+        let group: HashMap<u64, GroupMessage> = GroupMessage::with_ids(
+            mentions
+                .iter()
+                .filter_map(|mention| mention.group_message_id)
+                .collect(),
+        )
+        .load(db)
+        .await?
+        .into_iter()
+        .map(|message| (message.id, message))
+        .collect();
+        let titles: HashMap<u64, String> = Conversation::of_member(user)
+            .load(db)
+            .await?
+            .into_iter()
+            .map(|conversation| (conversation.id, conversation.title))
+            .collect();
+
+        let mentions = mentions
+            .into_iter()
+            .filter_map(|mention| {
+                let (sender, group, sent_at, content) = match mention {
+                    Mention {
+                        message_id: Some(id),
+                        ..
+                    } => {
+                        let message = direct.get(&id)?;
+                        (&message.sender, None, message.sent_at, &message.content)
+                    }
+                    Mention {
+                        group_message_id: Some(id),
+                        ..
+                    } => {
+                        let message = group.get(&id)?;
+                        let title = titles.get(&message.conversation_id)?;
+                        (
+                            &message.sender,
+                            Some(title.clone()),
+                            message.sent_at,
+                            &message.content,
+                        )
+                    }
+                    _ => return None,
+                };
+
+                Some(MentionEntry {
+                    id: mention.id,
+                    author: Avatar::new(sender.clone()),
+                    group,
+                    date: sent_at.to_string(),
+                    excerpt: markdown::plain_text(content),
+                    unread: mention.read_at.is_none(),
+                })
+            })
+            .collect();
+
+        Ok(Self { mentions })
+    }
+}
+
+/// Lists the messages mentioning the user, rendering just the list for htmx requests and the
+/// whole page otherwise.
+pub async fn get_mentions(
+    State(Application { db, .. }): State<Application>,
+    htmx: Option<HtmxRequest>,
+    username: Username,
+) -> Result<Either<Root, MentionList>, AppError> {
+    let mentions = MentionList::load(&mut *db.get().await?, &username).await?;
+
+    if let None | Some(HtmxRequest { restore: true, .. }) = htmx {
+        return Ok(Either::E1(Root {
+            content: Content::Mentions(MentionsPage {
+                user: username.into_inner(),
+                mentions,
+            }),
+        }));
+    }
+
+    Ok(Either::E2(mentions))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MentionPath {
+    id: u64,
+}
+
+/// Marks a mention as read and goes on to the message it was made in.
+pub async fn open_mention(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(MentionPath { id }): Path<MentionPath>,
+    username: Username,
+) -> Result<Redirect, AppError> {
+    let mut db = db.get().await?;
+
+    let mention = Mention::find_of(&username, id).first(&mut db).await?;
+
+    let url = match mention {
+        Mention {
+            message_id: Some(message_id),
+            ..
+        } => {
+            let message = DbMessage::with_ids(vec![message_id]).first(&mut db).await?;
+            format!(
+                "{}?message={}",
+                conversation_url(&message.sender),
+                message.id
+            )
+        }
+        Mention {
+            group_message_id: Some(message_id),
+            ..
+        } => {
+            let message = GroupMessage::with_ids(vec![message_id])
+                .first(&mut db)
+                .await?;
+            group_url(message.conversation_id)
+        }
+        _ => return Err(AppError::NotFound),
+    };
+
+    if mention.read_at.is_none() {
+        diesel::update(mentions::table.find(mention.id))
+            .set(mentions::read_at.eq(now))
+            .execute(&mut db)
+            .await?;
+        hub.publish(&username, Notification::MentionsChanged);
+    }
+
+    Ok(Redirect::to(&url))
+}
+
+pub async fn mark_all_read(
+    State(Application { db, hub, .. }): State<Application>,
+    username: Username,
+) -> Result<MentionList, AppError> {
+    let mut db = db.get().await?;
+
+    let read = diesel::update(Mention::unread(&username))
+        .set(mentions::read_at.eq(now))
+        .execute(&mut db)
+        .await?;
+    if read > 0 {
+        hub.publish(&username, Notification::MentionsChanged);
+    }
+
+    Ok(MentionList::load(&mut db, &username).await?)
+}
+
+pub async fn badge(
+    State(Application { db, .. }): State<Application>,
+    username: Username,
+) -> Result<MentionsBadge, AppError> {
+    Ok(MentionsBadge {
+        unread: Mention::unread_count(&username)
+            .get_result(&mut db.get().await?)
+            .await?,
+    })
+}
+
+/// Pushes the badge again whenever the user is mentioned or reads their mentions.
+pub async fn badge_events(
+    State(Application { db, hub, .. }): State<Application>,
+    username: Username,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = futures::stream::unfold(hub.subscribe(&username), move |mut notifications| {
+        let (db, username) = (db.clone(), username.to_owned());
+        let next = async move {
+            loop {
+                match notifications.recv().await {
+                    Ok(Notification::MentionsChanged) | Err(RecvError::Lagged(_)) => {}
+                    Ok(_) => continue,
+                    Err(RecvError::Closed) => return Ok(None),
+                }
+
+                let badge = MentionsBadge {
+                    unread: Mention::unread_count(&username)
+                        .get_result(&mut db.get().await?)
+                        .await?,
+                };
+
+                return Ok(Some((
+                    Ok(Event::default().event("mentions").data(badge.render()?)),
+                    notifications,
+                )));
+            }
+        };
+        async move { AppError::end_stream(next.await) }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
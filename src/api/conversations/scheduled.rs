@@ -0,0 +1,167 @@
+use askama::Template;
+use axum::{
+    extract::{Path, State},
+    routing::{delete, get, post},
+    Router,
+};
+use axum_extra::either::Either;
+use diesel::{QueryDsl, QueryResult};
+use diesel_async::{
+    scoped_futures::ScopedFutureExt, AsyncConnection, AsyncMysqlConnection, RunQueryDsl,
+};
+use serde::Deserialize;
+
+use crate::{
+    api::{login::Username, AppError, Application, Content, HtmxRequest, Hub, Root},
+    model::{schema::scheduled_messages, Message as DbMessage, ScheduledMessage},
+};
+
+use super::{
+    direct::{conversation_url, Outgoing},
+    markdown, Avatar,
+};
+
+pub fn router() -> Router<Application> {
+    Router::new()
+        .route("/", get(get_scheduled))
+        .route("/:id", delete(cancel_scheduled))
+        .route("/:id/send", post(send_now))
+}
+
+#[derive(Template)]
+#[template(path = "conversations/scheduled/index.html")]
+pub struct ScheduledPage {
+    user: String,
+    messages: ScheduledList,
+}
+
+#[derive(Template)]
+#[template(path = "conversations/scheduled/list.html")]
+pub struct ScheduledList {
+    messages: Vec<ScheduledEntry>,
+}
+
+/// A message the user scheduled that wasn't sent yet.
+pub struct ScheduledEntry {
+    id: u64,
+    receiver: Avatar,
+    url: String,
+    /// When the message is due, in the time zone it was scheduled in.
+    send_at: String,
+    excerpt: String,
+    failed: bool,
+}
+
+impl ScheduledList {
+    async fn load(db: &mut AsyncMysqlConnection, user: &str) -> QueryResult<Self> {
+        let messages = ScheduledMessage::of(user)
+            .load(db)
+            .await?
+            .into_iter()
+            .map(|message| ScheduledEntry {
+                id: message.id,
+                url: conversation_url(&message.receiver),
+                send_at: message.local_send_at().format("%Y-%m-%d %H:%M").to_string(),
+                receiver: Avatar::new(message.receiver),
+                excerpt: markdown::plain_text(&message.content),
+                failed: message.failed_at.is_some(),
+            })
+            .collect();
+
+        Ok(Self { messages })
+    }
+}
+
+/// Sends a scheduled message, unless it was sent or cancelled in the meantime.
+pub async fn dispatch(
+    db: &mut AsyncMysqlConnection,
+    hub: &Hub,
+    scheduled: ScheduledMessage,
+) -> QueryResult<Option<DbMessage>> {
+    let id = scheduled.id;
+    let outgoing = Outgoing::new(db, scheduled.into_message()).await?;
+
+    // Taking the message off the schedule in the same transaction makes sure it is only sent
+    // once, whoever gets to it first.
+    let sent = db
+        .transaction::<_, diesel::result::Error, _>(|db| {
+            let outgoing = &outgoing;
+            async move {
+                let taken = diesel::delete(scheduled_messages::table.find(id))
+                    .execute(db)
+                    .await?;
+                if taken == 0 {
+                    return Ok(None);
+                }
+
+                outgoing.store(db, &[]).await.map(Some)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    if let Some(sent) = &sent {
+        outgoing.publish(hub, sent.clone());
+    }
+
+    Ok(sent)
+}
+
+/// Lists the user's pending scheduled messages, rendering just the list for htmx requests and
+/// the whole page otherwise.
+pub async fn get_scheduled(
+    State(Application { db, .. }): State<Application>,
+    htmx: Option<HtmxRequest>,
+    username: Username,
+) -> Result<Either<Root, ScheduledList>, AppError> {
+    let messages = ScheduledList::load(&mut *db.get().await?, &username).await?;
+
+    if let None | Some(HtmxRequest { restore: true, .. }) = htmx {
+        return Ok(Either::E1(Root {
+            content: Content::Scheduled(ScheduledPage {
+                user: username.into_inner(),
+                messages,
+            }),
+        }));
+    }
+
+    Ok(Either::E2(messages))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduledPath {
+    id: u64,
+}
+
+pub async fn cancel_scheduled(
+    State(Application { db, .. }): State<Application>,
+    Path(ScheduledPath { id }): Path<ScheduledPath>,
+    username: Username,
+) -> Result<ScheduledList, AppError> {
+    let mut db = db.get().await?;
+
+    let scheduled = ScheduledMessage::find_of(&username, id)
+        .first(&mut db)
+        .await?;
+    diesel::delete(scheduled_messages::table.find(scheduled.id))
+        .execute(&mut db)
+        .await?;
+
+    Ok(ScheduledList::load(&mut db, &username).await?)
+}
+
+/// Sends a scheduled message right away instead of waiting for it to be due.
+pub async fn send_now(
+    State(Application { db, hub, .. }): State<Application>,
+    Path(ScheduledPath { id }): Path<ScheduledPath>,
+    username: Username,
+) -> Result<ScheduledList, AppError> {
+    let mut db = db.get().await?;
+
+    let scheduled = ScheduledMessage::find_of(&username, id)
+        .first(&mut db)
+        .await?;
+    dispatch(&mut db, &hub, scheduled).await?;
+
+    Ok(ScheduledList::load(&mut db, &username).await?)
+}
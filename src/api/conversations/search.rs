@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Router,
+};
+use axum_extra::either::Either;
+use diesel_async::RunQueryDsl;
+use serde::Deserialize;
+
+use crate::{
+    api::{login::Username, AppError, Application, Content, HtmxRequest, Root},
+    model::Message as DbMessage,
+};
+
+use super::{
+    direct::{conversation_url, split_matches},
+    markdown, Avatar,
+};
+
+/// Number of matching messages shown at most, newest first.
+const RESULT_LIMIT: usize = 50;
+
+/// Number of characters shown on either side of the first match in a message.
+const SNIPPET_CONTEXT: usize = 40;
+
+pub fn router() -> Router<Application> {
+    Router::new().route("/", get(search))
+}
+
+#[derive(Template)]
+#[template(path = "conversations/search/index.html")]
+pub struct SearchPage {
+    user: String,
+    query: String,
+    results: SearchResults,
+}
+
+#[derive(Template, Default)]
+#[template(path = "conversations/search/results.html")]
+pub struct SearchResults {
+    searched: bool,
+    peers: Vec<PeerResults>,
+}
+
+/// The matching messages exchanged with one peer.
+pub struct PeerResults {
+    peer: Avatar,
+    hits: Vec<SearchHit>,
+}
+
+pub struct SearchHit {
+    url: String,
+    yours: bool,
+    date: String,
+    snippet: String,
+    words: Vec<String>,
+}
+
+impl SearchHit {
+    fn snippet_parts(&self) -> Vec<(bool, &str)> {
+        split_matches(&self.snippet, &self.words)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+/// Searches the messages of all of the user's conversations with other users, rendering just
+/// the results for htmx requests and the whole page otherwise.
+pub async fn search(
+    State(Application { db, .. }): State<Application>,
+    htmx: Option<HtmxRequest>,
+    Query(SearchQuery { q }): Query<SearchQuery>,
+    username: Username,
+) -> Result<Either<Root, SearchResults>, AppError> {
+    let words = search_words(&q);
+
+    let results = if words.is_empty() {
+        SearchResults::default()
+    } else {
+        let messages = DbMessage::search(&username, &words, RESULT_LIMIT)
+            .load(&mut db.get().await?)
+            .await?;
+
+        SearchResults {
+            searched: true,
+            peers: group_by_peer(messages, &username, &words),
+        }
+    };
+
+    if let None | Some(HtmxRequest { restore: true, .. }) = htmx {
+        return Ok(Either::E1(Root {
+            content: Content::Search(SearchPage {
+                user: username.into_inner(),
+                query: q,
+                results,
+            }),
+        }));
+    }
+
+    Ok(Either::E2(results))
+}
+
+/// The words of the query, without the characters that are operators in `FULLTEXT` searches.
+fn search_words(query: &str) -> Vec<String> {
+    query
+        .split_whitespace()
+        .map(|word| word.chars().filter(|c| c.is_alphanumeric()).collect())
+        .filter(|word: &String| !word.is_empty())
+        .collect()
+}
+
+/// Groups the `messages` (newest first) by the other participant, ordering peers by their
+/// newest match.
+fn group_by_peer(messages: Vec<DbMessage>, user: &str, words: &[String]) -> Vec<PeerResults> {
+    let mut peers: Vec<PeerResults> = Vec::new();
+    let mut indices: HashMap<String, usize> = HashMap::new();
+
+    for message in messages {
+        let yours = message.sender == user;
+        let peer = if yours {
+            message.receiver
+        } else {
+            message.sender
+        };
+
+        let hit = SearchHit {
+            url: format!("{}?message={}", conversation_url(&peer), message.id),
+            yours,
+            date: message.sent_at.to_string(),
+            snippet: snippet(&message.content, words),
+            words: words.to_vec(),
+        };
+
+        let index = *indices.entry(peer.clone()).or_insert_with(|| {
+            peers.push(PeerResults {
+                peer: Avatar::new(peer),
+                hits: Vec::new(),
+            });
+            peers.len() - 1
+        });
+        peers[index].hits.push(hit);
+    }
+
+    peers
+}
+
+/// The part of `content` around the first match of any of the `words`.
+fn snippet(content: &str, words: &[String]) -> String {
+    let content = &markdown::plain_text(content);
+    let parts = split_matches(content, words);
+    let Some(matched) = parts.iter().position(|(matched, _)| *matched) else {
+        return content.chars().take(2 * SNIPPET_CONTEXT).collect();
+    };
+    let match_start: usize = parts[..matched].iter().map(|(_, part)| part.len()).sum();
+    let match_end = match_start + parts[matched].1.len();
+
+    let start = content[..match_start]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map_or(0, |(index, _)| index);
+    let end = content[match_end..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT)
+        .map_or(content.len(), |(index, _)| match_end + index);
+
+    format!(
+        "{}{}{}",
+        if start > 0 { "…" } else { "" },
+        &content[start..end],
+        if end < content.len() { "…" } else { "" },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{search_words, snippet, SNIPPET_CONTEXT};
+
+    #[test]
+    fn search_words_drop_fulltext_operators() {
+        assert_eq!(
+            search_words("  +hello -world \"quoted\" (x*) ~ "),
+            ["hello", "world", "quoted", "x"]
+        );
+        assert_eq!(search_words("café naïve"), ["café", "naïve"]);
+        assert!(search_words("+-<>()~*\"@").is_empty());
+    }
+
+    #[test]
+    fn snippet_keeps_short_messages_whole() {
+        assert_eq!(
+            snippet("see you **tomorrow**", &["you".to_owned()]),
+            "see you tomorrow"
+        );
+    }
+
+    #[test]
+    fn snippet_cuts_around_the_first_match() {
+        let before = "a".repeat(2 * SNIPPET_CONTEXT);
+        let after = "b".repeat(2 * SNIPPET_CONTEXT);
+        let content = format!("{before} needle {after}");
+
+        let snippet = snippet(&content, &["NEEDLE".to_owned()]);
+        assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+        assert!(snippet.contains(" needle "));
+        assert_eq!(
+            snippet.chars().count(),
+            2 * SNIPPET_CONTEXT + "needle".len() + 2
+        );
+    }
+}
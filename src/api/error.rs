@@ -0,0 +1,216 @@
+use std::{
+    fmt::{self, Display},
+    io,
+};
+
+use argon2::password_hash;
+use askama::Template;
+use axum::{
+    extract::{multipart::MultipartError, Request},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use diesel::result::Error as DieselError;
+use diesel_async::pooled_connection::deadpool::PoolError;
+use image::ImageError;
+use tokio::task::JoinError;
+
+use super::{Content, HtmxRequest, Root};
+
+/// Anything that can go wrong while handling a request that isn't the client's fault, plus the
+/// cases where the client asked for something that doesn't exist or doesn't make sense.
+///
+/// The response only carries the status code along with an [`ErrorMessage`] as an extension,
+/// which [`render_errors`] turns into a body fitting the request.
+#[derive(Debug)]
+pub enum AppError {
+    /// No database connection could be checked out of the pool, usually because of a timeout.
+    Pool(PoolError),
+    Database(DieselError),
+    Render(askama::Error),
+    /// Reading or writing attachments on disk failed.
+    Storage(io::Error),
+    /// The multipart body of an upload couldn't be read, possibly because it was too large.
+    Upload(MultipartError),
+    /// Encoding the thumbnails of an image attachment failed.
+    Thumbnail(ImageError),
+    /// Hashing or verifying a password failed.
+    Hashing(password_hash::Error),
+    /// A task on a blocking thread panicked.
+    Task(JoinError),
+    NotFound,
+    /// The user may see, but not change what they asked to change.
+    Forbidden,
+    Invalid(&'static str),
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(DieselError::NotFound) | AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Database(_)
+            | AppError::Render(_)
+            | AppError::Storage(_)
+            | AppError::Thumbnail(_)
+            | AppError::Hashing(_)
+            | AppError::Task(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Upload(e) => e.status(),
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            AppError::Pool(_) => "The server is busy right now, please try again in a moment.",
+            AppError::Database(DieselError::NotFound) | AppError::NotFound => {
+                "There is nothing here (anymore)."
+            }
+            AppError::Database(_)
+            | AppError::Render(_)
+            | AppError::Storage(_)
+            | AppError::Thumbnail(_)
+            | AppError::Hashing(_)
+            | AppError::Task(_) => "Something went wrong on our end, please try again.",
+            AppError::Upload(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                "The attachments are too large."
+            }
+            AppError::Upload(_) => "The upload could not be read, please try again.",
+            AppError::Forbidden => "You are not allowed to do this.",
+            AppError::Invalid(message) => message,
+        }
+    }
+
+    /// Reports the underlying cause.
+    pub fn log(&self) {
+        eprintln!("{} while handling a request: {self}", self.status());
+    }
+
+    /// Ends an event stream on errors, which can only be reported here since the response is
+    /// already underway.
+    pub fn end_stream<T>(next: Result<Option<T>, AppError>) -> Option<T> {
+        next.unwrap_or_else(|e| {
+            e.log();
+            None
+        })
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Pool(e) => write!(f, "could not get a database connection: {e}"),
+            AppError::Database(e) => write!(f, "database query failed: {e}"),
+            AppError::Render(e) => write!(f, "rendering template failed: {e}"),
+            AppError::Storage(e) => write!(f, "attachment storage failed: {e}"),
+            AppError::Upload(e) => write!(f, "reading upload failed: {e}"),
+            AppError::Thumbnail(e) => write!(f, "generating thumbnails failed: {e}"),
+            AppError::Hashing(e) => write!(f, "password hashing failed: {e}"),
+            AppError::Task(e) => write!(f, "blocking task failed: {e}"),
+            AppError::NotFound => write!(f, "not found"),
+            AppError::Forbidden => write!(f, "forbidden"),
+            AppError::Invalid(message) => write!(f, "invalid input: {message}"),
+        }
+    }
+}
+
+impl From<PoolError> for AppError {
+    fn from(e: PoolError) -> Self {
+        AppError::Pool(e)
+    }
+}
+
+impl From<DieselError> for AppError {
+    fn from(e: DieselError) -> Self {
+        AppError::Database(e)
+    }
+}
+
+impl From<askama::Error> for AppError {
+    fn from(e: askama::Error) -> Self {
+        AppError::Render(e)
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Storage(e)
+    }
+}
+
+impl From<MultipartError> for AppError {
+    fn from(e: MultipartError) -> Self {
+        AppError::Upload(e)
+    }
+}
+
+impl From<ImageError> for AppError {
+    fn from(e: ImageError) -> Self {
+        AppError::Thumbnail(e)
+    }
+}
+
+impl From<password_hash::Error> for AppError {
+    fn from(e: password_hash::Error) -> Self {
+        AppError::Hashing(e)
+    }
+}
+
+impl From<JoinError> for AppError {
+    fn from(e: JoinError) -> Self {
+        AppError::Task(e)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        self.log();
+
+        let status = self.status();
+        let mut response = status.into_response();
+        response.extensions_mut().insert(ErrorMessage {
+            status: status.as_u16(),
+            message: self.message(),
+        });
+        response
+    }
+}
+
+#[derive(Template, Debug, Clone)]
+#[template(path = "error.html")]
+pub struct ErrorMessage {
+    status: u16,
+    message: &'static str,
+}
+
+/// Renders the [`ErrorMessage`]s of failed requests, into the error area of the page for htmx
+/// requests, or as a page of their own otherwise.
+pub async fn render_errors(htmx: Option<HtmxRequest>, request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+
+    let Some(error) = response.extensions().get::<ErrorMessage>().cloned() else {
+        return response;
+    };
+    let status = response.status();
+
+    match htmx {
+        Some(HtmxRequest { restore: false }) => (
+            status,
+            [
+                ("HX-Retarget", "#error-messages"),
+                ("HX-Reswap", "innerHTML"),
+            ],
+            error,
+        )
+            .into_response(),
+        _ => (
+            status,
+            Root {
+                content: Content::Error(error),
+            },
+        )
+            .into_response(),
+    }
+}
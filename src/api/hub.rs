@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::model::{GroupMessage, Message as DbMessage};
+
+/// Number of notifications buffered per user before slow subscribers start lagging behind.
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone)]
+pub enum Notification {
+    NewMessage(DbMessage),
+    /// This message was edited, deleted for everyone or reacted to.
+    MessageChanged(DbMessage),
+    MessagesRead {
+        reader: String,
+        sender: String,
+        ids: Vec<u64>,
+    },
+    NewGroupMessage(GroupMessage),
+    /// The title or the members of the group conversation with this ID changed.
+    GroupChanged(u64),
+    /// This user started or stopped typing a message to the user.
+    Typing(String),
+    /// The user was mentioned, or mentions of them were read or went away.
+    MentionsChanged,
+    /// The disappearing messages timer of the conversation between these users changed.
+    TimerChanged {
+        setter: String,
+        peer: String,
+    },
+    /// The messages with these IDs ran out of time and are gone.
+    MessagesExpired(Vec<u64>),
+}
+
+/// In-process fan-out of [`Notification`]s, keyed by the username of the recipient.
+///
+/// Channels are created lazily on the first subscription and dropped again as soon as a
+/// notification is published to a user without any remaining subscribers.
+#[derive(Clone, Default)]
+pub struct Hub {
+    channels: Arc<Mutex<HashMap<String, Sender<Notification>>>>,
+}
+
+impl Hub {
+    pub fn subscribe(&self, user: &str) -> Receiver<Notification> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(user.to_owned())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    pub fn publish(&self, user: &str, notification: Notification) {
+        let mut channels = self.channels.lock().unwrap();
+
+        if let Some(sender) = channels.get(user) {
+            if sender.send(notification).is_err() {
+                channels.remove(user);
+            }
+        }
+    }
+}
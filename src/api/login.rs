@@ -1,50 +1,143 @@
 use std::{borrow::Cow, future::Future, ops::Deref};
 
+use argon2::{
+    password_hash::{
+        self,
+        rand_core::{OsRng, RngCore},
+        SaltString,
+    },
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
 use askama::Template;
 use axum::{
-    extract::FromRequestParts,
-    http::request::Parts,
+    extract::{FromRequestParts, Path, State},
+    http::{header::USER_AGENT, request::Parts, HeaderMap},
     response::Redirect,
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Form, Router,
 };
 use axum_extra::{
     either::Either,
     extract::{
-        cookie::{Cookie, SameSite},
-        CookieJar,
+        cookie::{Cookie, Key, SameSite},
+        PrivateCookieJar,
     },
 };
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{
+    result::{DatabaseErrorKind, Error as DieselError},
+    ExpressionMethods, Insertable, OptionalExtension, QueryDsl,
+};
+use diesel_async::{pooled_connection::deadpool::Pool, AsyncMysqlConnection, RunQueryDsl};
 use serde::Deserialize;
+use tokio::task::spawn_blocking;
+
+use crate::model::{
+    schema::{sessions, users},
+    NewSession, NewUser, Session, User,
+};
 
-use super::{Application, Content, Root};
+use super::{AppError, Application, Content, Root};
 
 pub fn router() -> Router<Application> {
     Router::new()
         .route("/", get(login))
         .route("/", post(try_login))
         .route("/validate", put(validate_username))
+        .route("/register", get(register))
+        .route("/register", post(try_register))
+        .route("/register/validate", put(validate_new_username))
+        .route("/logout", post(logout))
+        .route("/sessions", get(get_sessions))
+        .route("/sessions/:id", delete(revoke_session))
+        .route("/last-seen", put(set_last_seen_hidden))
+}
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Template)]
+#[template(path = "login/username-input.html")]
+pub struct UsernameInput {
+    value: String,
+    error: Option<&'static str>,
+    validate_url: &'static str,
+}
+
+impl UsernameInput {
+    fn for_login(value: String) -> Self {
+        Self {
+            error: Username::new(&value)
+                .is_none()
+                .then_some("Not a valid username."),
+            value,
+            validate_url: "/login/validate",
+        }
+    }
+
+    fn for_registration(value: String, error: Option<&'static str>) -> Self {
+        Self {
+            error: error.or_else(|| {
+                Username::new(&value).is_none().then_some(
+                    "Usernames are up to 20 letters and digits and must start with a letter.",
+                )
+            }),
+            value,
+            validate_url: "/login/register/validate",
+        }
+    }
 }
 
 #[derive(Template)]
 #[template(path = "login/index.html")]
 pub struct LoginPage {
-    value: String,
-    has_error: bool,
-    from_validation: bool,
+    username: UsernameInput,
+    error: Option<&'static str>,
 }
 
 impl Default for LoginPage {
     fn default() -> Self {
         Self {
-            value: String::new(),
-            has_error: true,
-            from_validation: false,
+            username: UsernameInput {
+                value: String::new(),
+                error: None,
+                validate_url: "/login/validate",
+            },
+            error: None,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "login/register.html")]
+pub struct RegisterPage {
+    username: UsernameInput,
+    error: Option<&'static str>,
+}
+
+impl Default for RegisterPage {
+    fn default() -> Self {
+        Self {
+            username: UsernameInput {
+                value: String::new(),
+                error: None,
+                validate_url: "/login/register/validate",
+            },
+            error: None,
         }
     }
 }
 
-const USER_NAME_COOKIE: &str = "MSGX_USERNAME";
+const SESSION_COOKIE: &str = "MSGX_SESSION";
+
+const SESSION_LIFETIME_DAYS: i64 = 30;
+
+/// A hash with the same parameters as those of actual passwords, which no password matches, to
+/// verify against when there is no user to log in as.
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$bJYmjBrCbCwwBDMhiGb/dw$XKKJGg5spGoFZ1MnLhwoj56w6Hk2WtKD3Cgqyerac4Y";
+
+/// Activity within this many seconds of the last recorded activity is not written back to the
+/// database, so that not every single request results in an additional update statement.
+const LAST_SEEN_RESOLUTION_SECS: i64 = 60;
 
 pub async fn login(username: Option<Username>) -> Either<Root, Redirect> {
     if username.is_some() {
@@ -56,36 +149,322 @@ pub async fn login(username: Option<Username>) -> Either<Root, Redirect> {
     })
 }
 
+pub async fn register(username: Option<Username>) -> Either<Root, Redirect> {
+    if username.is_some() {
+        return Either::E2(Redirect::to("/conversations"));
+    };
+
+    Either::E1(Root {
+        content: Content::Register(Default::default()),
+    })
+}
+
 #[derive(Deserialize)]
 pub struct LoginParameters {
     username: String,
+    password: String,
 }
 
 pub async fn try_login(
-    cookies: CookieJar,
-    Form(LoginParameters { username }): Form<LoginParameters>,
-) -> Either<LoginPage, (CookieJar, Redirect)> {
-    if Username::new(&username).is_some() {
-        let mut cookie = Cookie::new(USER_NAME_COOKIE, username);
-        cookie.set_http_only(true);
-        cookie.set_same_site(SameSite::Lax);
-        return Either::E2((cookies.add(cookie), Redirect::to("/conversations")));
+    State(Application { db, .. }): State<Application>,
+    cookies: PrivateCookieJar,
+    headers: HeaderMap,
+    Form(LoginParameters { username, password }): Form<LoginParameters>,
+) -> Result<Either<LoginPage, (PrivateCookieJar, Redirect)>, AppError> {
+    let user = User::named(&username)
+        .first(&mut db.get().await?)
+        .await
+        .optional()?;
+
+    match user {
+        Some(user) => {
+            if verify_password(password, user.password_hash).await? {
+                return Ok(Either::E2((
+                    log_in_as(&db, cookies, user.username, &headers).await?,
+                    Redirect::to("/conversations"),
+                )));
+            }
+        }
+        // Takes as long as a wrong password would, so that the response time doesn't give away
+        // whether the user exists.
+        None => {
+            verify_password(password, DUMMY_PASSWORD_HASH.to_owned()).await?;
+        }
+    }
+
+    Ok(Either::E1(LoginPage {
+        username: UsernameInput::for_login(username),
+        error: Some("Unknown username or wrong password."),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RegisterParameters {
+    username: String,
+    password: String,
+    #[serde(rename = "password-confirmation")]
+    password_confirmation: String,
+}
+
+pub async fn try_register(
+    State(Application { db, .. }): State<Application>,
+    cookies: PrivateCookieJar,
+    headers: HeaderMap,
+    Form(RegisterParameters {
+        username,
+        password,
+        password_confirmation,
+    }): Form<RegisterParameters>,
+) -> Result<Either<RegisterPage, (PrivateCookieJar, Redirect)>, AppError> {
+    let rejected = |username: String, error: Option<&'static str>| {
+        Ok(Either::E1(RegisterPage {
+            username: UsernameInput::for_registration(username, None),
+            error,
+        }))
     };
 
-    Either::E1(LoginPage {
-        value: username,
-        has_error: true,
-        from_validation: true,
-    })
+    if Username::new(&username).is_none() {
+        return rejected(username, None);
+    }
+
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return rejected(
+            username,
+            Some("Passwords need to be at least 8 characters long."),
+        );
+    }
+
+    if password != password_confirmation {
+        return rejected(username, Some("The passwords do not match."));
+    }
+
+    let inserted = NewUser {
+        username: username.clone(),
+        password_hash: hash_password(password).await?,
+    }
+    .insert_into(users::table)
+    .execute(&mut db.get().await?)
+    .await;
+
+    match inserted {
+        Ok(_) => Ok(Either::E2((
+            log_in_as(&db, cookies, username, &headers).await?,
+            Redirect::to("/conversations"),
+        ))),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Ok(Either::E1(RegisterPage {
+                username: UsernameInput::for_registration(
+                    username,
+                    Some("This username is already taken."),
+                ),
+                error: None,
+            }))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ValidateParameters {
+    username: String,
 }
 
 pub async fn validate_username(
-    Form(LoginParameters { username }): Form<LoginParameters>,
-) -> LoginPage {
-    LoginPage {
-        has_error: Username::new(&username).is_none(),
-        value: username,
-        from_validation: true,
+    Form(ValidateParameters { username }): Form<ValidateParameters>,
+) -> UsernameInput {
+    UsernameInput::for_login(username)
+}
+
+pub async fn validate_new_username(
+    State(Application { db, .. }): State<Application>,
+    Form(ValidateParameters { username }): Form<ValidateParameters>,
+) -> Result<UsernameInput, AppError> {
+    let taken = Username::new(&username).is_some()
+        && User::named(&username)
+            .first(&mut db.get().await?)
+            .await
+            .optional()?
+            .is_some();
+
+    Ok(UsernameInput::for_registration(
+        username,
+        taken.then_some("This username is already taken."),
+    ))
+}
+
+async fn log_in_as(
+    db: &Pool<AsyncMysqlConnection>,
+    cookies: PrivateCookieJar,
+    username: String,
+    headers: &HeaderMap,
+) -> Result<PrivateCookieJar, AppError> {
+    let mut db = db.get().await?;
+    let now = Utc::now().naive_utc();
+
+    diesel::delete(sessions::table.filter(sessions::expires_at.le(now)))
+        .execute(&mut db)
+        .await?;
+
+    let mut id = [0u8; 32];
+    OsRng.fill_bytes(&mut id);
+    let id: String = id.iter().map(|byte| format!("{byte:02x}")).collect();
+
+    NewSession {
+        id: id.clone(),
+        user: username.clone(),
+        created_at: now,
+        last_seen_at: now,
+        expires_at: now + Duration::days(SESSION_LIFETIME_DAYS),
+        user_agent: headers
+            .get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(|agent| agent.chars().take(255).collect()),
+    }
+    .insert_into(sessions::table)
+    .execute(&mut db)
+    .await?;
+    println!("User logged in as {username}.");
+
+    let mut cookie = Cookie::new(SESSION_COOKIE, id);
+    cookie.set_http_only(true);
+    cookie.set_same_site(SameSite::Lax);
+    cookie.set_path("/");
+    cookie.set_max_age(time::Duration::days(SESSION_LIFETIME_DAYS));
+    Ok(cookies.add(cookie))
+}
+
+pub async fn logout(
+    State(Application { db, .. }): State<Application>,
+    cookies: PrivateCookieJar,
+) -> Result<(PrivateCookieJar, Redirect), AppError> {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        diesel::delete(sessions::table.find(cookie.value()))
+            .execute(&mut db.get().await?)
+            .await?;
+    }
+
+    Ok((
+        cookies.remove(Cookie::build(SESSION_COOKIE).path("/")),
+        Redirect::to("/login"),
+    ))
+}
+
+pub struct SessionItem {
+    id: String,
+    current: bool,
+    user_agent: String,
+    created_at: NaiveDateTime,
+    last_seen_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+}
+
+#[derive(Template)]
+#[template(path = "login/sessions.html")]
+pub struct SessionsPage {
+    username: String,
+    sessions: Vec<SessionItem>,
+    hide_last_seen: bool,
+}
+
+pub async fn get_sessions(
+    State(Application { db, .. }): State<Application>,
+    cookies: PrivateCookieJar,
+    username: Username,
+) -> Result<Root, AppError> {
+    let current = cookies.get(SESSION_COOKIE);
+    let mut db = db.get().await?;
+
+    let user = User::named(&username).first(&mut db).await?;
+    let sessions = Session::of_user(&username, Utc::now().naive_utc())
+        .load(&mut db)
+        .await?
+        .into_iter()
+        .map(|session| SessionItem {
+            current: current
+                .as_ref()
+                .is_some_and(|cookie| cookie.value() == session.id),
+            id: session.id,
+            user_agent: session
+                .user_agent
+                .unwrap_or_else(|| "Unknown device".to_owned()),
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            expires_at: session.expires_at,
+        })
+        .collect();
+
+    Ok(Root {
+        content: Content::Sessions(SessionsPage {
+            username: username.into_inner(),
+            sessions,
+            hide_last_seen: user.hide_last_seen,
+        }),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionPath {
+    id: String,
+}
+
+pub async fn revoke_session(
+    State(Application { db, .. }): State<Application>,
+    Path(RevokeSessionPath { id }): Path<RevokeSessionPath>,
+    username: Username,
+) -> Result<(), AppError> {
+    diesel::delete(
+        sessions::table
+            .find(&id)
+            .filter(sessions::user.eq(username.as_str())),
+    )
+    .execute(&mut db.get().await?)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct LastSeenForm {
+    /// Present, whatever its value, when the checkbox is checked.
+    hide: Option<String>,
+}
+
+/// Lets the user keep others from seeing whether and when they were last online.
+pub async fn set_last_seen_hidden(
+    State(Application { db, .. }): State<Application>,
+    username: Username,
+    Form(LastSeenForm { hide }): Form<LastSeenForm>,
+) -> Result<(), AppError> {
+    diesel::update(users::table.find(username.as_str()))
+        .set(users::hide_last_seen.eq(hide.is_some()))
+        .execute(&mut db.get().await?)
+        .await?;
+
+    Ok(())
+}
+
+/// Hashing is deliberately expensive, so it is kept off the async executor.
+async fn hash_password(password: String) -> Result<String, AppError> {
+    let hash = spawn_blocking(move || {
+        Argon2::default()
+            .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+            .map(|hash| hash.to_string())
+    })
+    .await??;
+
+    Ok(hash)
+}
+
+async fn verify_password(password: String, hash: String) -> Result<bool, AppError> {
+    let verified = spawn_blocking(move || {
+        Argon2::default().verify_password(password.as_bytes(), &PasswordHash::new(&hash)?)
+    })
+    .await?;
+
+    match verified {
+        Ok(()) => Ok(true),
+        Err(password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -125,12 +504,14 @@ impl Deref for Username {
     }
 }
 
-impl<A: Send + Sync> FromRequestParts<A> for Username {
-    type Rejection = Redirect;
+/// Resolves the user behind the session referenced by the (encrypted and authenticated) session
+/// cookie. Missing, expired and revoked sessions all redirect to the login page.
+impl FromRequestParts<Application> for Username {
+    type Rejection = Either<Redirect, AppError>;
 
     fn from_request_parts<'life0, 'life1, 'async_trait>(
         parts: &'life0 mut Parts,
-        a: &'life1 A,
+        state: &'life1 Application,
     ) -> ::core::pin::Pin<
         Box<dyn Future<Output = Result<Self, Self::Rejection>> + Send + 'async_trait>,
     >
@@ -140,17 +521,67 @@ impl<A: Send + Sync> FromRequestParts<A> for Username {
         Self: 'async_trait,
     {
         Box::pin(async {
-            let cookies = CookieJar::from_request_parts(parts, a).await.unwrap();
+            let cookies = PrivateCookieJar::<Key>::from_request_parts(parts, state)
+                .await
+                .unwrap();
+
+            let Some(session_id) = cookies.get(SESSION_COOKIE) else {
+                return Err(Either::E1(Redirect::to("/login")));
+            };
+
+            let mut db = state.db.get().await.map_err(|e| Either::E2(e.into()))?;
+            let now = Utc::now().naive_utc();
 
-            if let Some(username) = cookies
-                .get(USER_NAME_COOKIE)
-                .and_then(|cookie| Username::new(cookie.value()))
-            {
-                println!("User logged in as {}.", &username.0);
+            let Some(session) = Session::active(session_id.value(), now)
+                .first(&mut db)
+                .await
+                .optional()
+                .map_err(|e| Either::E2(e.into()))?
+            else {
+                return Err(Either::E1(Redirect::to("/login")));
+            };
+
+            if now - session.last_seen_at > Duration::seconds(LAST_SEEN_RESOLUTION_SECS) {
+                diesel::update(sessions::table.find(&session.id))
+                    .set(sessions::last_seen_at.eq(now))
+                    .execute(&mut db)
+                    .await
+                    .map_err(|e| Either::E2(e.into()))?;
+            }
+
+            if let Some(username) = Username::new(session.user) {
+                state.presence.touch(&username);
                 Ok(username)
             } else {
-                Err(Redirect::to("/login"))
+                Err(Either::E1(Redirect::to("/login")))
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Username;
+
+    #[test]
+    fn usernames_are_letters_then_digits() {
+        for name in ["alice", "Bob", "carol99", "a1b2", "abcdefghijklmnopqrst"] {
+            assert!(Username::new(name).is_some(), "{name} should be valid");
+        }
+    }
+
+    #[test]
+    fn invalid_usernames_are_rejected() {
+        for name in [
+            "",
+            "1alice",
+            "alice_b",
+            "alice b",
+            "al-ice",
+            "élodie",
+            "abcdefghijklmnopqrstu",
+        ] {
+            assert!(Username::new(name).is_none(), "{name} should be invalid");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{ExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::{pooled_connection::deadpool::Pool, AsyncMysqlConnection, RunQueryDsl};
+
+use crate::model::schema::users;
+
+use super::AppError;
+
+/// Users active within this many seconds count as online.
+pub const ONLINE_WITHIN_SECS: i64 = 2 * 60;
+/// Users active within this many seconds, but not online anymore, count as away.
+pub const AWAY_WITHIN_SECS: i64 = 15 * 60;
+
+/// How often activity is written to the database.
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+struct Activity {
+    last_seen: NaiveDateTime,
+    flushed: bool,
+}
+
+/// When users were last active, updated on every authenticated request and written to
+/// `users.last_seen_at` every once in a while rather than on each of them.
+#[derive(Clone, Default)]
+pub struct Presence {
+    activity: Arc<Mutex<HashMap<String, Activity>>>,
+}
+
+impl Presence {
+    pub fn touch(&self, user: &str) {
+        self.activity.lock().unwrap().insert(
+            user.to_owned(),
+            Activity {
+                last_seen: Utc::now().naive_utc(),
+                flushed: false,
+            },
+        );
+    }
+
+    /// When `user` was last active, going by the activity not written to the database yet as
+    /// well as the `stored` one.
+    pub fn last_seen(&self, user: &str, stored: Option<NaiveDateTime>) -> Option<NaiveDateTime> {
+        let recent = self
+            .activity
+            .lock()
+            .unwrap()
+            .get(user)
+            .map(|activity| activity.last_seen);
+
+        recent.max(stored)
+    }
+
+    /// Writes the activity since the last flush to the database, and forgets about users that
+    /// have been gone for long enough for the stored activity to say everything.
+    async fn flush(&self, db: &mut AsyncMysqlConnection) -> QueryResult<()> {
+        let pending: Vec<(String, NaiveDateTime)> = {
+            let mut activity = self.activity.lock().unwrap();
+            let cutoff = Utc::now().naive_utc() - Duration::seconds(AWAY_WITHIN_SECS);
+            activity.retain(|_, activity| !activity.flushed || activity.last_seen > cutoff);
+
+            activity
+                .iter()
+                .filter(|(_, activity)| !activity.flushed)
+                .map(|(user, activity)| (user.clone(), activity.last_seen))
+                .collect()
+        };
+
+        for (user, last_seen) in pending {
+            diesel::update(users::table.find(&user))
+                .set(users::last_seen_at.eq(last_seen))
+                .execute(db)
+                .await?;
+
+            // Unless the user was active again in the meantime.
+            if let Some(activity) = self.activity.lock().unwrap().get_mut(&user) {
+                activity.flushed |= activity.last_seen == last_seen;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the activity every [`FLUSH_INTERVAL`], for as long as the server runs.
+    pub async fn flush_periodically(self, db: Pool<AsyncMysqlConnection>) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let flushed = match db.get().await {
+                Ok(mut db) => self.flush(&mut db).await.map_err(AppError::from),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = flushed {
+                e.log();
+            }
+        }
+    }
+}
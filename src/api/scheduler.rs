@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::{dsl::now, ExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::{pooled_connection::deadpool::Pool, AsyncMysqlConnection, RunQueryDsl};
+use tokio::sync::Notify;
+
+use crate::model::{schema::scheduled_messages, ScheduledMessage};
+
+use super::{conversations::scheduled::dispatch, AppError, Hub};
+
+/// Number of due messages sent in one go.
+const BATCH_SIZE: usize = 50;
+
+/// How long the dispatcher sleeps at most, so that it doesn't rely on being woken up.
+const IDLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Wakes up the dispatcher sending scheduled messages whenever one is scheduled, since it
+/// otherwise sleeps until the earliest one it knows about is due.
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    wake: Arc<Notify>,
+}
+
+impl Scheduler {
+    /// Has the dispatcher look for the next message due again.
+    pub fn reschedule(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Sends all messages that are due, returning how long until the next one is.
+    async fn dispatch_due(
+        &self,
+        db: &mut AsyncMysqlConnection,
+        hub: &Hub,
+    ) -> QueryResult<std::time::Duration> {
+        loop {
+            let due = ScheduledMessage::due(Utc::now().naive_utc(), BATCH_SIZE)
+                .load(db)
+                .await?;
+            let done = due.len() < BATCH_SIZE;
+            for scheduled in due {
+                let id = scheduled.id;
+                // A message that can't be sent is set aside for the sender to retry, instead of
+                // holding up those due after it.
+                if let Err(e) = dispatch(db, hub, scheduled).await {
+                    AppError::from(e).log();
+                    diesel::update(scheduled_messages::table.find(id))
+                        .set(scheduled_messages::failed_at.eq(now))
+                        .execute(db)
+                        .await?;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+
+        let next: Option<NaiveDateTime> = ScheduledMessage::next_send_at().get_result(db).await?;
+        // Anything due by now is picked up right away on the next round.
+        Ok(next.map_or(IDLE_INTERVAL, |next| {
+            (next - Utc::now().naive_utc())
+                .to_std()
+                .unwrap_or_default()
+                .min(IDLE_INTERVAL)
+        }))
+    }
+
+    /// Sends scheduled messages as they become due, for as long as the server runs. Messages
+    /// that became due while it wasn't running are sent right away.
+    pub async fn dispatch_periodically(self, db: Pool<AsyncMysqlConnection>, hub: Hub) {
+        loop {
+            let dispatched = match db.get().await {
+                Ok(mut db) => self
+                    .dispatch_due(&mut db, &hub)
+                    .await
+                    .map_err(AppError::from),
+                Err(e) => Err(e.into()),
+            };
+            let wait = dispatched.unwrap_or_else(|e| {
+                e.log();
+                IDLE_INTERVAL
+            });
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use diesel::{ExpressionMethods, QueryDsl};
+use diesel_async::{
+    pooled_connection::deadpool::Pool, scoped_futures::ScopedFutureExt, AsyncConnection,
+    AsyncMysqlConnection, RunQueryDsl,
+};
+
+use crate::model::{
+    schema::{conversation_heads, hidden_messages, messages},
+    Attachment, Message as DbMessage,
+};
+
+use super::{conversations::attachments::remove_files, AppError, Hub, Notification};
+
+/// Number of expired messages deleted in one go.
+const BATCH_SIZE: usize = 200;
+
+/// How often expired messages are looked for.
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Deletes one batch of messages whose disappearing messages timer ran out, along with the files
+/// only they were using, and returns how many there were.
+async fn sweep(
+    db: &mut AsyncMysqlConnection,
+    hub: &Hub,
+    attachment_dir: &Path,
+) -> Result<usize, AppError> {
+    let expired = DbMessage::expired(Utc::now().naive_utc(), BATCH_SIZE)
+        .load(db)
+        .await?;
+    if expired.is_empty() {
+        return Ok(0);
+    }
+    let ids: Vec<u64> = expired.iter().map(|message| message.id).collect();
+
+    let hashes: Vec<String> = Attachment::of_messages(ids.clone())
+        .load(db)
+        .await?
+        .into_iter()
+        .map(|attachment| attachment.hash)
+        .collect();
+
+    // Unread messages count towards the receiver's head, unless they were hidden from them.
+    let hidden: HashSet<(String, u64)> = hidden_messages::table
+        .filter(hidden_messages::message_id.eq_any(&ids))
+        .select((hidden_messages::user, hidden_messages::message_id))
+        .load(db)
+        .await?
+        .into_iter()
+        .collect();
+
+    db.transaction::<_, diesel::result::Error, _>(|db| {
+        let (ids, hidden) = (&ids, &hidden);
+        async move {
+            // Locking the unread ones keeps them from being read, and counted down for, in the
+            // meantime.
+            let still_unread: Vec<(u64, String, String)> = messages::table
+                .filter(messages::id.eq_any(ids))
+                .filter(messages::read_at.is_null())
+                .select((messages::id, messages::sender, messages::receiver))
+                .for_update()
+                .load(db)
+                .await?;
+
+            let mut unread: HashMap<(String, String), u32> = HashMap::new();
+            for (id, sender, receiver) in still_unread {
+                if sender != receiver && !hidden.contains(&(receiver.clone(), id)) {
+                    *unread.entry((receiver, sender)).or_default() += 1;
+                }
+            }
+            for ((user, peer), count) in unread {
+                diesel::update(conversation_heads::table.find((user, peer)))
+                    .set(
+                        conversation_heads::unread_count
+                            .eq(conversation_heads::unread_count - count),
+                    )
+                    .execute(db)
+                    .await?;
+            }
+            diesel::delete(messages::table.filter(messages::id.eq_any(ids)))
+                .execute(db)
+                .await?;
+
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    let still_used: HashSet<String> = Attachment::with_hashes(hashes.clone())
+        .load(db)
+        .await?
+        .into_iter()
+        .map(|attachment| attachment.hash)
+        .collect();
+    for hash in hashes.iter().collect::<HashSet<_>>() {
+        if !still_used.contains(hash) {
+            remove_files(attachment_dir, hash).await?;
+        }
+    }
+
+    let mut gone: HashMap<&str, Vec<u64>> = HashMap::new();
+    for message in &expired {
+        gone.entry(&message.sender).or_default().push(message.id);
+        if message.receiver != message.sender {
+            gone.entry(&message.receiver).or_default().push(message.id);
+        }
+    }
+    for (user, ids) in gone {
+        hub.publish(user, Notification::MessagesExpired(ids));
+    }
+
+    Ok(expired.len())
+}
+
+/// Deletes expired messages every [`SWEEP_INTERVAL`], for as long as the server runs, going
+/// through them in batches so that none of the deletes holds locks for long.
+pub async fn sweep_periodically(db: Pool<AsyncMysqlConnection>, hub: Hub, attachment_dir: PathBuf) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        loop {
+            let swept = match db.get().await {
+                Ok(mut db) => sweep(&mut db, &hub, &attachment_dir).await,
+                Err(e) => Err(e.into()),
+            };
+            match swept {
+                Ok(count) if count == BATCH_SIZE => continue,
+                Ok(_) => break,
+                Err(e) => {
+                    e.log();
+                    break;
+                }
+            }
+        }
+    }
+}
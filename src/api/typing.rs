@@ -0,0 +1,48 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long someone still counts as typing after their last keystroke.
+pub const TYPING_TTL: Duration = Duration::from_secs(5);
+
+/// Who is typing a message to whom right now, kept in memory only since it is outdated within
+/// seconds anyway.
+#[derive(Clone, Default)]
+pub struct Typing {
+    /// When each `(typist, peer)` pair last typed.
+    last_typed: Arc<Mutex<HashMap<(String, String), Instant>>>,
+}
+
+impl Typing {
+    /// Records that `typist` is typing a message to `peer`, returning whether they just started.
+    pub fn start(&self, typist: &str, peer: &str) -> bool {
+        let mut last_typed = self.last_typed.lock().unwrap();
+        let now = Instant::now();
+
+        // Forget everyone who stopped, so that the map only holds those still typing.
+        last_typed.retain(|_, typed| now.duration_since(*typed) < TYPING_TTL);
+
+        last_typed
+            .insert((typist.to_owned(), peer.to_owned()), now)
+            .is_none()
+    }
+
+    /// Forgets that `typist` was typing to `peer`, returning whether they were.
+    pub fn stop(&self, typist: &str, peer: &str) -> bool {
+        self.last_typed
+            .lock()
+            .unwrap()
+            .remove(&(typist.to_owned(), peer.to_owned()))
+            .is_some_and(|typed| typed.elapsed() < TYPING_TTL)
+    }
+
+    pub fn is_typing(&self, typist: &str, peer: &str) -> bool {
+        self.last_typed
+            .lock()
+            .unwrap()
+            .get(&(typist.to_owned(), peer.to_owned()))
+            .is_some_and(|typed| typed.elapsed() < TYPING_TTL)
+    }
+}
@@ -1,5 +1,6 @@
 use std::{env::var, error::Error};
 
+use axum_extra::extract::cookie::Key;
 use diesel_async::pooled_connection::{deadpool::Pool, AsyncDieselConnectionManager};
 use tokio::{self, net::TcpListener};
 
@@ -8,14 +9,47 @@ mod model;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + 'static>> {
-    dotenv::dotenv()?;
+    // The environment may also be set up without a `.env` file, see `.env.example`.
+    dotenv::dotenv().ok();
+
+    let cookie_secret = var("COOKIE_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .ok_or("COOKIE_SECRET needs to be set to a secret of at least 64 bytes")?;
 
     let config = AsyncDieselConnectionManager::<diesel_async::AsyncMysqlConnection>::new(var(
         "DATABASE_URL",
     )?);
 
+    let attachments = var("ATTACHMENT_DIR").unwrap_or_else(|_| "attachments".to_owned());
+    tokio::fs::create_dir_all(&attachments).await?;
+
+    let db = Pool::builder(config).build()?;
+
+    let presence = api::Presence::default();
+    tokio::spawn(presence.clone().flush_periodically(db.clone()));
+
+    let hub = api::Hub::default();
+    let scheduler = api::Scheduler::default();
+    tokio::spawn(
+        scheduler
+            .clone()
+            .dispatch_periodically(db.clone(), hub.clone()),
+    );
+    tokio::spawn(api::sweep_periodically(
+        db.clone(),
+        hub.clone(),
+        attachments.clone().into(),
+    ));
+
     let app = api::router().with_state(api::Application {
-        db: Pool::builder(config).build()?,
+        db,
+        key: Key::try_from(cookie_secret.as_bytes())?,
+        hub,
+        typing: Default::default(),
+        presence,
+        scheduler,
+        attachments: attachments.into(),
     });
 
     let listener = TcpListener::bind("[::]:3000").await.unwrap();
@@ -1,10 +1,38 @@
+mod attachments;
+mod blocks;
+mod edits;
+mod flags;
+mod groups;
+mod heads;
+mod mentions;
+mod reactions;
+mod scheduled;
 pub mod schema;
+mod sessions;
+mod timers;
+mod users;
+
+pub use attachments::{Attachment, NewAttachment};
+pub use blocks::{Block, NewBlock};
+pub use edits::{MessageEdit, NewMessageEdit};
+pub use flags::{ConversationFlags, NewConversationFlags, Shelf};
+pub use groups::{Conversation, GroupMessage, NewConversation, NewGroupMessage, NewMembership};
+pub use heads::{ConversationHead, NewConversationHead};
+pub use mentions::{Mention, NewMention};
+pub use reactions::{NewReaction, Reaction};
+pub use scheduled::{NewScheduledMessage, ScheduledMessage};
+pub use sessions::{NewSession, Session};
+pub use timers::{DisappearingTimer, NewDisappearingTimer};
+pub use users::{NewUser, User, UserPresence};
 
 use chrono::NaiveDateTime;
 use diesel::backend::Backend;
-use diesel::helper_types::Like;
-use diesel::query_source::{Alias, AliasedField};
-use diesel::{alias, prelude::*, Expression};
+use diesel::expression::{AppearsOnTable, AsExpression, SelectableExpression, ValidGrouping};
+use diesel::helper_types::{AsExprOf, Like};
+use diesel::mysql::Mysql;
+use diesel::query_builder::{AstPass, QueryFragment, QueryId};
+use diesel::sql_types::{Bool, Nullable};
+use diesel::{prelude::*, Expression};
 
 #[derive(Insertable)]
 #[diesel(table_name = schema::messages)]
@@ -13,9 +41,13 @@ pub struct NewMessage {
     pub sender: String,
     pub receiver: String,
     pub content: String,
+    /// The earlier message this one quotes.
+    pub reply_to: Option<u64>,
+    /// Whether this is a notice about the conversation rather than something the sender wrote.
+    pub notice: bool,
 }
 
-#[derive(Queryable, Selectable)]
+#[derive(Queryable, Selectable, Debug, Clone)]
 #[diesel(table_name = schema::messages)]
 #[diesel(check_for_backend(diesel::mysql::Mysql))]
 pub struct Message {
@@ -25,21 +57,85 @@ pub struct Message {
     pub content: String,
     pub sent_at: NaiveDateTime,
     pub read_at: Option<NaiveDateTime>,
+    pub edited_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub reply_to: Option<u64>,
+    pub notice: bool,
+    /// Seconds after which the message disappears, under a disappearing messages timer.
+    pub disappear_after: Option<u32>,
+    /// When the message disappears, once the countdown started.
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// A message one of its participants deleted for themselves only.
+#[derive(Insertable)]
+#[diesel(table_name = schema::hidden_messages)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewHiddenMessage {
+    pub user: String,
+    pub message_id: u64,
+}
+
+use diesel::dsl::{
+    count_star, And, AsSelect, Asc, Desc, Eq, EqAny, Filter, Gt, GtEq, IsNull, Limit, Lt, LtEq,
+    NeAny, Or, Order, Select,
+};
+
+type HiddenBy<'a> = Select<
+    Filter<schema::hidden_messages::table, Eq<schema::hidden_messages::user, &'a str>>,
+    schema::hidden_messages::message_id,
+>;
+
+/// The IDs of the messages `user` deleted for themselves.
+fn hidden_by(user: &str) -> HiddenBy<'_> {
+    schema::hidden_messages::table
+        .filter(schema::hidden_messages::user.eq(user))
+        .select(schema::hidden_messages::message_id)
 }
 
-use diesel::dsl::{exists, not, And, AsSelect, Desc, Eq, Filter, Gt, Limit, Lt, Or, Order, Select};
+type NotHiddenBy<'a> = NeAny<schema::messages::id, HiddenBy<'a>>;
 
 type All<DB> =
     Order<Select<schema::messages::table, AsSelect<Message, DB>>, Desc<schema::messages::id>>;
 type Between<'a, DB> = Filter<
-    All<DB>,
-    IsBetween<'a, schema::messages::columns::sender, schema::messages::columns::receiver, &'a str>,
+    Filter<
+        All<DB>,
+        IsBetween<
+            'a,
+            schema::messages::columns::sender,
+            schema::messages::columns::receiver,
+            &'a str,
+        >,
+    >,
+    NotHiddenBy<'a>,
 >;
+type NewestRemaining<'a, DB> = Limit<Filter<Between<'a, DB>, IsNull<schema::messages::deleted_at>>>;
 
-type Containing<'a, DB> =
-    Limit<Filter<Between<'a, DB>, Like<schema::messages::columns::content, String>>>;
-type ContainingAfter<'a, DB> = Filter<Containing<'a, DB>, Gt<schema::messages::columns::id, u64>>;
-type ContainingBefore<'a, DB> = Filter<Containing<'a, DB>, Lt<schema::messages::columns::id, u64>>;
+type Matches<'a> = Filter<
+    Filter<
+        Filter<
+            Filter<
+                schema::messages::table,
+                IsBetween<'a, schema::messages::sender, schema::messages::receiver, &'a str>,
+            >,
+            NotHiddenBy<'a>,
+        >,
+        IsNull<schema::messages::deleted_at>,
+    >,
+    Like<lower::HelperType<schema::messages::content>, String>,
+>;
+type MatchesNewestFirst<'a, DB> =
+    Order<Select<Matches<'a>, AsSelect<Message, DB>>, Desc<schema::messages::id>>;
+type NewestMatch<'a, DB> = Limit<MatchesNewestFirst<'a, DB>>;
+type MatchBefore<'a, DB> = Limit<Filter<MatchesNewestFirst<'a, DB>, Lt<schema::messages::id, u64>>>;
+type MatchAfter<'a, DB> = Limit<
+    Order<
+        Select<Filter<Matches<'a>, Gt<schema::messages::id, u64>>, AsSelect<Message, DB>>,
+        Asc<schema::messages::id>,
+    >,
+>;
+type MatchCount<'a> = Select<Matches<'a>, count_star>;
+type MatchesSince<'a> = Select<Filter<Matches<'a>, GtEq<schema::messages::id, u64>>, count_star>;
 
 type After<'a, DB> = Filter<Between<'a, DB>, Gt<schema::messages::id, u64>>;
 type Before<'a, DB> = Filter<Between<'a, DB>, Lt<schema::messages::id, u64>>;
@@ -49,6 +145,132 @@ type BeforeLimited<'a, DB> = Limit<Before<'a, DB>>;
 
 type Limited<'a, DB> = Limit<Between<'a, DB>>;
 
+type FindBetween<'a, DB> = Filter<Between<'a, DB>, Eq<schema::messages::id, u64>>;
+type ChangedSince<'a, DB> = Filter<
+    Between<'a, DB>,
+    Or<
+        Or<
+            GtEq<schema::messages::edited_at, NaiveDateTime>,
+            GtEq<schema::messages::deleted_at, NaiveDateTime>,
+            Nullable<Bool>,
+        >,
+        GtEq<schema::messages::reacted_at, NaiveDateTime>,
+        Nullable<Bool>,
+    >,
+>;
+
+type WithIds<DB> = Filter<All<DB>, EqAny<schema::messages::id, Vec<u64>>>;
+type RemainingWithIds<'a, DB> =
+    Filter<Filter<WithIds<DB>, IsNull<schema::messages::deleted_at>>, NotHiddenBy<'a>>;
+
+type Unread<'a, DB> = Filter<
+    Filter<
+        All<DB>,
+        And<
+            And<Eq<schema::messages::sender, &'a str>, Eq<schema::messages::receiver, &'a str>>,
+            IsNull<schema::messages::read_at>,
+        >,
+    >,
+    NotHiddenBy<'a>,
+>;
+
+type Expired<DB> = Limit<Filter<All<DB>, LtEq<schema::messages::expires_at, NaiveDateTime>>>;
+
+type LastInserted<DB> = Filter<All<DB>, Eq<schema::messages::id, last_insert_id::HelperType>>;
+
+type Search<'a, DB> = Limit<
+    Filter<
+        Filter<
+            Filter<
+                Filter<
+                    All<DB>,
+                    Or<
+                        Eq<schema::messages::sender, &'a str>,
+                        Eq<schema::messages::receiver, &'a str>,
+                    >,
+                >,
+                IsNull<schema::messages::deleted_at>,
+            >,
+            NotHiddenBy<'a>,
+        >,
+        MatchAgainst<schema::messages::content, AsExprOf<String, Text>>,
+    >,
+>;
+
+/// A `LIKE` pattern matching anything that contains `needle`, wildcards in it included.
+pub fn containing(needle: &str) -> String {
+    let mut pattern = String::with_capacity(needle.len() + 2);
+    pattern.push('%');
+    for c in needle.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+sql_function! {
+    /// The `AUTO_INCREMENT` value generated by the most recent insert on the current connection.
+    fn last_insert_id() -> Unsigned<BigInt>;
+}
+
+sql_function! {
+    fn lower(x: Text) -> Text;
+}
+
+/// `MATCH (column) AGAINST (query IN BOOLEAN MODE)`, which needs a `FULLTEXT` index on `column`.
+#[derive(Debug, Clone, QueryId, ValidGrouping)]
+pub struct MatchAgainst<C, Q> {
+    column: C,
+    query: Q,
+}
+
+fn match_against<C, Q>(column: C, query: Q) -> MatchAgainst<C, Q::Expression>
+where
+    C: Expression<SqlType = Text>,
+    Q: AsExpression<Text>,
+{
+    MatchAgainst {
+        column,
+        query: query.as_expression(),
+    }
+}
+
+impl<C, Q> Expression for MatchAgainst<C, Q> {
+    type SqlType = Bool;
+}
+
+impl<C, Q, QS> AppearsOnTable<QS> for MatchAgainst<C, Q>
+where
+    C: AppearsOnTable<QS>,
+    Q: AppearsOnTable<QS>,
+{
+}
+
+impl<C, Q, QS> SelectableExpression<QS> for MatchAgainst<C, Q>
+where
+    C: SelectableExpression<QS>,
+    Q: SelectableExpression<QS>,
+{
+}
+
+impl<C, Q> QueryFragment<Mysql> for MatchAgainst<C, Q>
+where
+    C: QueryFragment<Mysql>,
+    Q: QueryFragment<Mysql>,
+{
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Mysql>) -> QueryResult<()> {
+        out.push_sql("MATCH (");
+        self.column.walk_ast(out.reborrow())?;
+        out.push_sql(") AGAINST (");
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(" IN BOOLEAN MODE)");
+        Ok(())
+    }
+}
+
 impl Message {
     pub fn all<DB: Backend>() -> All<DB> {
         schema::messages::table
@@ -56,11 +278,80 @@ impl Message {
             .order_by(schema::messages::id.desc())
     }
 
-    pub fn between<'a, DB: Backend>((peer1, peer2): (&'a str, &'a str)) -> Between<'a, DB> {
-        Self::all().filter(is_between(
-            (peer1, peer2.into_sql::<Text>()),
-            (schema::messages::sender, schema::messages::receiver),
-        ))
+    /// Messages between `peer` and `user`, leaving out those `user` deleted for themselves. All
+    /// other builders taking a pair of peers follow the same order.
+    pub fn between<'a, DB: Backend>((peer, user): (&'a str, &'a str)) -> Between<'a, DB> {
+        Self::all()
+            .filter(is_between(
+                (peer, user.into_sql::<Text>()),
+                (schema::messages::sender, schema::messages::receiver),
+            ))
+            .filter(schema::messages::id.ne_all(hidden_by(user)))
+    }
+
+    /// The newest message between `peers` that wasn't deleted, to preview the conversation with.
+    pub fn newest_remaining<'a, DB: Backend>(peers: (&'a str, &'a str)) -> NewestRemaining<'a, DB> {
+        Self::between(peers)
+            .filter(schema::messages::deleted_at.is_null())
+            .limit(1)
+    }
+
+    /// The message inserted last on the connection this query is run on.
+    pub fn last_inserted<DB: Backend>() -> LastInserted<DB> {
+        Self::all().filter(schema::messages::id.eq(last_insert_id()))
+    }
+
+    /// Messages between `peers` edited, deleted or reacted to at or after `since`.
+    pub fn changed_since<'a, DB: Backend>(
+        peers: (&'a str, &'a str),
+        since: NaiveDateTime,
+    ) -> ChangedSince<'a, DB> {
+        Self::between(peers).filter(
+            schema::messages::edited_at
+                .ge(since)
+                .or(schema::messages::deleted_at.ge(since))
+                .or(schema::messages::reacted_at.ge(since)),
+        )
+    }
+
+    /// The message with the given ID, as long as it was exchanged between `peers`.
+    pub fn find_between<'a, DB: Backend>(
+        peers: (&'a str, &'a str),
+        id: u64,
+    ) -> FindBetween<'a, DB> {
+        Self::between(peers).filter(schema::messages::id.eq(id))
+    }
+
+    pub fn with_ids<DB: Backend>(ids: Vec<u64>) -> WithIds<DB> {
+        Self::all().filter(schema::messages::id.eq_any(ids))
+    }
+
+    /// Those of the messages with the given IDs that weren't deleted, neither for everyone nor by
+    /// `user` for themselves.
+    pub fn remaining_with_ids<DB: Backend>(user: &str, ids: Vec<u64>) -> RemainingWithIds<'_, DB> {
+        Self::with_ids(ids)
+            .filter(schema::messages::deleted_at.is_null())
+            .filter(schema::messages::id.ne_all(hidden_by(user)))
+    }
+
+    /// Messages sent from `sender` to `receiver` that the latter has not read yet, leaving out
+    /// those hidden from them, which they can't read.
+    pub fn unread<'a, DB: Backend>((sender, receiver): (&'a str, &'a str)) -> Unread<'a, DB> {
+        Self::all()
+            .filter(
+                schema::messages::sender
+                    .eq(sender)
+                    .and(schema::messages::receiver.eq(receiver))
+                    .and(schema::messages::read_at.is_null()),
+            )
+            .filter(schema::messages::id.ne_all(hidden_by(receiver)))
+    }
+
+    /// Up to `limit` messages, between anyone, whose countdown ran out by `at`.
+    pub fn expired<DB: Backend>(at: NaiveDateTime, limit: usize) -> Expired<DB> {
+        Self::all()
+            .filter(schema::messages::expires_at.le(at))
+            .limit(limit as i64)
     }
 
     pub fn limited<'a, DB: Backend>(peers: (&'a str, &'a str), limit: usize) -> Limited<'a, DB> {
@@ -96,18 +387,95 @@ impl Message {
             || (self.sender == peer2 && self.receiver == peer1)
     }
 
-    pub fn like<'a, DB: Backend>(peers: (&'a str, &'a str), like: &'a str) -> Containing<'a, DB> {
-        Self::between(peers)
-            .filter(schema::messages::content.like(format!("%{like}%")))
+    /// The newest messages sent or received by `user` containing all of the `words`, or words
+    /// starting with them, through the `FULLTEXT` index on their content. Deleted messages are
+    /// left out.
+    pub fn search<'a, DB: Backend>(
+        user: &'a str,
+        words: &[String],
+        limit: usize,
+    ) -> Search<'a, DB> {
+        let query = words
+            .iter()
+            .map(|word| format!("+{word}*"))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        Self::all()
+            .filter(
+                schema::messages::sender
+                    .eq(user)
+                    .or(schema::messages::receiver.eq(user)),
+            )
+            .filter(schema::messages::deleted_at.is_null())
+            .filter(schema::messages::id.ne_all(hidden_by(user)))
+            .filter(match_against(schema::messages::content, query))
+            .limit(limit as i64)
+    }
+
+    /// Messages between `peers` containing `needle`, regardless of case. Deleted messages are
+    /// left out.
+    fn matches<'a>((peer, user): (&'a str, &'a str), needle: &str) -> Matches<'a> {
+        schema::messages::table
+            .filter(is_between(
+                (peer, user.into_sql::<Text>()),
+                (schema::messages::sender, schema::messages::receiver),
+            ))
+            .filter(schema::messages::id.ne_all(hidden_by(user)))
+            .filter(schema::messages::deleted_at.is_null())
+            .filter(lower(schema::messages::content).like(containing(&needle.to_lowercase())))
+    }
+
+    fn matches_newest_first<'a, DB: Backend>(
+        peers: (&'a str, &'a str),
+        needle: &str,
+    ) -> MatchesNewestFirst<'a, DB> {
+        Self::matches(peers, needle)
+            .select(Self::as_select())
+            .order_by(schema::messages::id.desc())
+    }
+
+    pub fn newest_match<'a, DB: Backend>(
+        peers: (&'a str, &'a str),
+        needle: &str,
+    ) -> NewestMatch<'a, DB> {
+        Self::matches_newest_first(peers, needle).limit(1)
+    }
+
+    /// The closest match older than the message with the given ID.
+    pub fn match_before<'a, DB: Backend>(
+        peers: (&'a str, &'a str),
+        needle: &str,
+        id: u64,
+    ) -> MatchBefore<'a, DB> {
+        Self::matches_newest_first(peers, needle)
+            .filter(schema::messages::id.lt(id))
             .limit(1)
     }
 
-    pub fn like_before<'a, DB: Backend>(
+    /// The closest match newer than the message with the given ID.
+    pub fn match_after<'a, DB: Backend>(
         peers: (&'a str, &'a str),
-        like: &'a str,
+        needle: &str,
         id: u64,
-    ) -> ContainingBefore<'a, DB> {
-        Self::like(peers, like).filter(schema::messages::id.lt(id))
+    ) -> MatchAfter<'a, DB> {
+        Self::matches(peers, needle)
+            .filter(schema::messages::id.gt(id))
+            .select(Self::as_select())
+            .order_by(schema::messages::id.asc())
+            .limit(1)
+    }
+
+    pub fn match_count<'a>(peers: (&'a str, &'a str), needle: &str) -> MatchCount<'a> {
+        Self::matches(peers, needle).count()
+    }
+
+    /// The number of matches from the message with the given ID on, which is its position among
+    /// all matches when counting from the newest one.
+    pub fn matches_since<'a>(peers: (&'a str, &'a str), needle: &str, id: u64) -> MatchesSince<'a> {
+        Self::matches(peers, needle)
+            .filter(schema::messages::id.ge(id))
+            .count()
     }
 }
 
@@ -131,90 +499,20 @@ where
         .or(columns.0.eq(peers.1).and(columns.1.eq(peers.0)))
 }
 
-alias! {
-    pub const LATER_MESSAGES: Alias<LaterMessages> = schema::messages as later_messages;
-}
-
-pub type LaterMessagesWithSender<'a> = Filter<
-    Alias<LaterMessages>,
-    And<
-        IsBetween<
-            'a,
-            AliasedField<LaterMessages, schema::messages::columns::sender>,
-            AliasedField<LaterMessages, schema::messages::columns::receiver>,
-            schema::messages::columns::sender,
-        >,
-        Gt<AliasedField<LaterMessages, schema::messages::columns::id>, schema::messages::id>,
-    >,
->;
-
-fn later_messages_with_sender(sender: &str) -> LaterMessagesWithSender<'_> {
-    LATER_MESSAGES.filter(
-        is_between(
-            (sender, schema::messages::sender),
-            (
-                LATER_MESSAGES.field(schema::messages::sender),
-                LATER_MESSAGES.field(schema::messages::receiver),
-            ),
-        )
-        .and(
-            LATER_MESSAGES
-                .field(schema::messages::id)
-                .gt(schema::messages::id),
-        ),
-    )
-}
+#[cfg(test)]
+mod tests {
+    use super::containing;
 
-pub type LaterMessagesWithReceiver<'a> = Filter<
-    Alias<LaterMessages>,
-    And<
-        IsBetween<
-            'a,
-            AliasedField<LaterMessages, schema::messages::columns::sender>,
-            AliasedField<LaterMessages, schema::messages::columns::receiver>,
-            schema::messages::columns::receiver,
-        >,
-        Gt<AliasedField<LaterMessages, schema::messages::columns::id>, schema::messages::id>,
-    >,
->;
-
-fn later_messages_with_receiver(sender: &str) -> LaterMessagesWithReceiver<'_> {
-    LATER_MESSAGES.filter(
-        is_between(
-            (sender, schema::messages::receiver),
-            (
-                LATER_MESSAGES.field(schema::messages::sender),
-                LATER_MESSAGES.field(schema::messages::receiver),
-            ),
-        )
-        .and(
-            LATER_MESSAGES
-                .field(schema::messages::id)
-                .gt(schema::messages::id),
-        ),
-    )
-}
-
-pub type SenderAndNoLaterMessagesWithReceiver<'a> =
-    And<Eq<schema::messages::columns::sender, &'a str>, not<exists<LaterMessagesWithReceiver<'a>>>>;
-
-pub type ReceiverAndNoLaterMessagesWithSender<'a> =
-    And<Eq<schema::messages::columns::receiver, &'a str>, not<exists<LaterMessagesWithSender<'a>>>>;
-
-pub type MostRecentMessages<'a, DB> = Filter<
-    All<DB>,
-    Or<SenderAndNoLaterMessagesWithReceiver<'a>, ReceiverAndNoLaterMessagesWithSender<'a>>,
->;
+    #[test]
+    fn containing_wraps_the_needle_in_wildcards() {
+        assert_eq!(containing("bob"), "%bob%");
+        assert_eq!(containing(""), "%%");
+    }
 
-impl Message {
-    pub fn most_recent<DB: Backend>(user: &str) -> MostRecentMessages<'_, DB> {
-        Self::all().filter(
-            schema::messages::sender
-                .eq(user)
-                .and(not(exists(later_messages_with_receiver(user))))
-                .or(schema::messages::receiver
-                    .eq(user)
-                    .and(not(exists(later_messages_with_sender(user))))),
-        )
+    #[test]
+    fn containing_escapes_wildcards_in_the_needle() {
+        assert_eq!(containing("50%"), "%50\\%%");
+        assert_eq!(containing("a_b"), "%a\\_b%");
+        assert_eq!(containing("back\\slash"), "%back\\\\slash%");
     }
 }
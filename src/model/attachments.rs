@@ -0,0 +1,59 @@
+use diesel::backend::Backend;
+use diesel::dsl::{AsSelect, Asc, Eq, EqAny, Filter, Order, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::attachments)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewAttachment {
+    pub message_id: u64,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// A file sent along with a message, stored on disk under its `hash`.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::attachments)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct Attachment {
+    pub id: u64,
+    pub message_id: u64,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+type All<DB> = Order<
+    Select<schema::attachments::table, AsSelect<Attachment, DB>>,
+    Asc<schema::attachments::id>,
+>;
+type WithId<DB> = Filter<All<DB>, Eq<schema::attachments::id, u64>>;
+type OfMessages<DB> = Filter<All<DB>, EqAny<schema::attachments::message_id, Vec<u64>>>;
+type WithHashes<DB> = Filter<All<DB>, EqAny<schema::attachments::hash, Vec<String>>>;
+
+impl Attachment {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::attachments::table
+            .select(Self::as_select())
+            .order_by(schema::attachments::id.asc())
+    }
+
+    pub fn with_id<DB: Backend>(id: u64) -> WithId<DB> {
+        Self::all().filter(schema::attachments::id.eq(id))
+    }
+
+    /// Attachments of any of the messages with the given IDs, in the order they were sent.
+    pub fn of_messages<DB: Backend>(ids: Vec<u64>) -> OfMessages<DB> {
+        Self::all().filter(schema::attachments::message_id.eq_any(ids))
+    }
+
+    /// Attachments whose content has any of the given hashes, which may be shared between them.
+    pub fn with_hashes<DB: Backend>(hashes: Vec<String>) -> WithHashes<DB> {
+        Self::all().filter(schema::attachments::hash.eq_any(hashes))
+    }
+}
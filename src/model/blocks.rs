@@ -0,0 +1,49 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{AsSelect, Eq, Filter, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::blocks)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewBlock {
+    pub user: String,
+    pub peer: String,
+}
+
+/// `user` doesn't want to hear from `peer` anymore.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::blocks)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct Block {
+    pub blocked_at: NaiveDateTime,
+}
+
+pub(super) type BlockedBy<'a> =
+    Select<Filter<schema::blocks::table, Eq<schema::blocks::user, &'a str>>, schema::blocks::peer>;
+
+/// The peers `user` blocked.
+pub(super) fn blocked_by(user: &str) -> BlockedBy<'_> {
+    schema::blocks::table
+        .filter(schema::blocks::user.eq(user))
+        .select(schema::blocks::peer)
+}
+
+type All<DB> = Select<schema::blocks::table, AsSelect<Block, DB>>;
+type Between<'a, DB> =
+    Filter<Filter<All<DB>, Eq<schema::blocks::user, &'a str>>, Eq<schema::blocks::peer, &'a str>>;
+
+impl Block {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::blocks::table.select(Self::as_select())
+    }
+
+    /// Whether `user` blocked `peer`.
+    pub fn between<'a, DB: Backend>(user: &'a str, peer: &'a str) -> Between<'a, DB> {
+        Self::all()
+            .filter(schema::blocks::user.eq(user))
+            .filter(schema::blocks::peer.eq(peer))
+    }
+}
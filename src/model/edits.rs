@@ -0,0 +1,44 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{AsSelect, Desc, Eq, Filter, Order, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+/// A version of a message's content from before it was edited.
+#[derive(Insertable)]
+#[diesel(table_name = schema::message_edits)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewMessageEdit {
+    pub message_id: u64,
+    pub content: String,
+    pub edited_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::message_edits)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct MessageEdit {
+    pub content: String,
+    /// When this version was replaced.
+    pub edited_at: NaiveDateTime,
+}
+
+type All<DB> = Order<
+    Select<schema::message_edits::table, AsSelect<MessageEdit, DB>>,
+    Desc<schema::message_edits::id>,
+>;
+type OfMessage<DB> = Filter<All<DB>, Eq<schema::message_edits::message_id, u64>>;
+
+impl MessageEdit {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::message_edits::table
+            .select(Self::as_select())
+            .order_by(schema::message_edits::id.desc())
+    }
+
+    /// Prior versions of the message with the given ID, most recently replaced first.
+    pub fn of_message<DB: Backend>(id: u64) -> OfMessage<DB> {
+        Self::all().filter(schema::message_edits::message_id.eq(id))
+    }
+}
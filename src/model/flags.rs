@@ -0,0 +1,115 @@
+use diesel::backend::Backend;
+use diesel::dsl::{count_star, And, AsSelect, Eq, Filter, Or, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::conversation_flags)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewConversationFlags {
+    pub user: String,
+    pub peer: String,
+    pub pinned: bool,
+    pub archived: bool,
+}
+
+/// How `user` filed away their conversation with `peer`. A conversation is either pinned,
+/// archived or neither, and those without a row are neither.
+#[derive(Queryable, Selectable, Debug, Clone, Copy, Default)]
+#[diesel(table_name = schema::conversation_flags)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct ConversationFlags {
+    pub pinned: bool,
+    pub archived: bool,
+}
+
+/// Where a conversation is filed away to, instead of being listed with the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shelf {
+    Pinned,
+    Archived,
+}
+
+pub(super) type Shelved<'a> = Select<
+    Filter<
+        schema::conversation_flags::table,
+        And<
+            Eq<schema::conversation_flags::user, &'a str>,
+            Or<schema::conversation_flags::pinned, schema::conversation_flags::archived>,
+        >,
+    >,
+    schema::conversation_flags::peer,
+>;
+
+/// The peers whose conversations `user` pinned or archived.
+pub(super) fn shelved(user: &str) -> Shelved<'_> {
+    schema::conversation_flags::table
+        .filter(
+            schema::conversation_flags::user
+                .eq(user)
+                .and(schema::conversation_flags::pinned.or(schema::conversation_flags::archived)),
+        )
+        .select(schema::conversation_flags::peer)
+}
+
+pub(super) type OnShelf<'a> = Select<
+    Filter<
+        Filter<
+            Filter<
+                schema::conversation_flags::table,
+                Eq<schema::conversation_flags::user, &'a str>,
+            >,
+            Eq<schema::conversation_flags::pinned, bool>,
+        >,
+        Eq<schema::conversation_flags::archived, bool>,
+    >,
+    schema::conversation_flags::peer,
+>;
+
+/// The peers whose conversations `user` put on `shelf`.
+pub(super) fn on_shelf(user: &str, shelf: Shelf) -> OnShelf<'_> {
+    schema::conversation_flags::table
+        .filter(schema::conversation_flags::user.eq(user))
+        .filter(schema::conversation_flags::pinned.eq(shelf == Shelf::Pinned))
+        .filter(schema::conversation_flags::archived.eq(shelf == Shelf::Archived))
+        .select(schema::conversation_flags::peer)
+}
+
+type All<DB> = Select<schema::conversation_flags::table, AsSelect<ConversationFlags, DB>>;
+type Between<'a, DB> = Filter<
+    All<DB>,
+    And<
+        Eq<schema::conversation_flags::user, &'a str>,
+        Eq<schema::conversation_flags::peer, &'a str>,
+    >,
+>;
+type PinnedCount<'a> = Select<
+    Filter<
+        Filter<schema::conversation_flags::table, Eq<schema::conversation_flags::user, &'a str>>,
+        Eq<schema::conversation_flags::pinned, bool>,
+    >,
+    count_star,
+>;
+
+impl ConversationFlags {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::conversation_flags::table.select(Self::as_select())
+    }
+
+    pub fn between<'a, DB: Backend>(user: &'a str, peer: &'a str) -> Between<'a, DB> {
+        Self::all().filter(
+            schema::conversation_flags::user
+                .eq(user)
+                .and(schema::conversation_flags::peer.eq(peer)),
+        )
+    }
+
+    /// The number of conversations `user` pinned.
+    pub fn pinned_count(user: &str) -> PinnedCount<'_> {
+        schema::conversation_flags::table
+            .filter(schema::conversation_flags::user.eq(user))
+            .filter(schema::conversation_flags::pinned.eq(true))
+            .select(count_star())
+    }
+}
@@ -0,0 +1,294 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{
+    And, AsSelect, Asc, Desc, Eq, EqAny, Filter, Gt, Like, Limit, Lt, Offset, Order, Select,
+};
+use diesel::prelude::*;
+use diesel::{dsl, helper_types};
+
+use super::{last_insert_id, schema};
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::conversations)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewConversation {
+    pub title: String,
+    pub created_by: String,
+}
+
+/// A group conversation; conversations between two users are implied by their messages instead.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::conversations)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct Conversation {
+    pub id: u64,
+    pub title: String,
+    pub last_message_id: Option<u64>,
+    pub last_activity: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::conversation_members)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewMembership {
+    pub conversation_id: u64,
+    pub member: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::group_messages)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewGroupMessage {
+    pub conversation_id: u64,
+    pub sender: String,
+    pub content: String,
+}
+
+/// A message sent to a group conversation.
+///
+/// Group messages live apart from direct [`Message`](super::Message)s, which are addressed by
+/// their sender and receiver. None of the features built on those reach groups: read receipts,
+/// edits, deletes, reactions, replies, attachments, search, disappearing messages and blocks only
+/// apply to direct conversations. Mentions are the one exception.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::group_messages)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct GroupMessage {
+    pub id: u64,
+    pub conversation_id: u64,
+    pub sender: String,
+    pub content: String,
+    pub sent_at: NaiveDateTime,
+}
+
+type Memberships<'a> = Select<
+    Filter<schema::conversation_members::table, Eq<schema::conversation_members::member, &'a str>>,
+    schema::conversation_members::conversation_id,
+>;
+
+fn memberships(member: &str) -> Memberships<'_> {
+    schema::conversation_members::table
+        .filter(schema::conversation_members::member.eq(member))
+        .select(schema::conversation_members::conversation_id)
+}
+
+type AllConversations<DB> = Select<schema::conversations::table, AsSelect<Conversation, DB>>;
+type Matching<'a> = Filter<
+    schema::conversations::table,
+    And<
+        EqAny<schema::conversations::id, Memberships<'a>>,
+        Like<schema::conversations::title, String>,
+    >,
+>;
+type MatchingAll<'a, DB> = Select<Matching<'a>, AsSelect<Conversation, DB>>;
+type MostRecent<'a, DB> = Offset<
+    Limit<
+        Order<
+            MatchingAll<'a, DB>,
+            (
+                Desc<schema::conversations::last_activity>,
+                Desc<schema::conversations::id>,
+            ),
+        >,
+    >,
+>;
+type Alphabetically<'a, DB> = Offset<
+    Limit<
+        Order<
+            MatchingAll<'a, DB>,
+            (
+                Asc<schema::conversations::title>,
+                Asc<schema::conversations::id>,
+            ),
+        >,
+    >,
+>;
+type NewestMessageId<'a> =
+    Select<Matching<'a>, helper_types::max<schema::conversations::last_message_id>>;
+type WithMember<'a, DB> = Filter<
+    AllConversations<DB>,
+    And<Eq<schema::conversations::id, u64>, EqAny<schema::conversations::id, Memberships<'a>>>,
+>;
+type OfMember<'a, DB> =
+    Filter<AllConversations<DB>, EqAny<schema::conversations::id, Memberships<'a>>>;
+type LastInsertedConversation<DB> =
+    Filter<AllConversations<DB>, Eq<schema::conversations::id, last_insert_id::HelperType>>;
+
+type Members = Order<
+    Select<
+        Filter<
+            schema::conversation_members::table,
+            Eq<schema::conversation_members::conversation_id, u64>,
+        >,
+        schema::conversation_members::member,
+    >,
+    Asc<schema::conversation_members::member>,
+>;
+type MembersOf = Order<
+    Select<
+        Filter<
+            schema::conversation_members::table,
+            EqAny<schema::conversation_members::conversation_id, Vec<u64>>,
+        >,
+        (
+            schema::conversation_members::conversation_id,
+            schema::conversation_members::member,
+        ),
+    >,
+    Asc<schema::conversation_members::member>,
+>;
+
+impl Conversation {
+    pub fn all<DB: Backend>() -> AllConversations<DB> {
+        schema::conversations::table.select(Self::as_select())
+    }
+
+    fn matching(member: &str, pattern: String) -> Matching<'_> {
+        schema::conversations::table.filter(
+            schema::conversations::id
+                .eq_any(memberships(member))
+                .and(schema::conversations::title.like(pattern)),
+        )
+    }
+
+    /// A page of the conversations `member` takes part in with titles matching the `LIKE`
+    /// `pattern`, most recently active first.
+    pub fn most_recent<DB: Backend>(
+        member: &str,
+        pattern: String,
+        offset: usize,
+        limit: usize,
+    ) -> MostRecent<'_, DB> {
+        Self::matching(member, pattern)
+            .select(Self::as_select())
+            .order_by((
+                schema::conversations::last_activity.desc(),
+                schema::conversations::id.desc(),
+            ))
+            .limit(limit as i64)
+            .offset(offset as i64)
+    }
+
+    /// A page of the conversations `member` takes part in with titles matching the `LIKE`
+    /// `pattern`, by title.
+    pub fn alphabetically<DB: Backend>(
+        member: &str,
+        pattern: String,
+        offset: usize,
+        limit: usize,
+    ) -> Alphabetically<'_, DB> {
+        Self::matching(member, pattern)
+            .select(Self::as_select())
+            .order_by((
+                schema::conversations::title.asc(),
+                schema::conversations::id.asc(),
+            ))
+            .limit(limit as i64)
+            .offset(offset as i64)
+    }
+
+    /// The ID of the newest message in any of the conversations `member` takes part in with
+    /// titles matching the `LIKE` `pattern`.
+    pub fn newest_message_id(member: &str, pattern: String) -> NewestMessageId<'_> {
+        Self::matching(member, pattern).select(dsl::max(schema::conversations::last_message_id))
+    }
+
+    /// The conversation with the given ID, as long as `member` takes part in it.
+    pub fn with_member<DB: Backend>(id: u64, member: &str) -> WithMember<'_, DB> {
+        Self::all().filter(
+            schema::conversations::id
+                .eq(id)
+                .and(schema::conversations::id.eq_any(memberships(member))),
+        )
+    }
+
+    /// All of the conversations `member` takes part in.
+    pub fn of_member<DB: Backend>(member: &str) -> OfMember<'_, DB> {
+        Self::all().filter(schema::conversations::id.eq_any(memberships(member)))
+    }
+
+    /// The conversation inserted last on the connection this query is run on.
+    pub fn last_inserted<DB: Backend>() -> LastInsertedConversation<DB> {
+        Self::all().filter(schema::conversations::id.eq(last_insert_id()))
+    }
+
+    pub fn members(id: u64) -> Members {
+        schema::conversation_members::table
+            .filter(schema::conversation_members::conversation_id.eq(id))
+            .select(schema::conversation_members::member)
+            .order_by(schema::conversation_members::member.asc())
+    }
+
+    /// Pairs of conversation ID and member for all of the given conversations.
+    pub fn members_of(ids: Vec<u64>) -> MembersOf {
+        schema::conversation_members::table
+            .filter(schema::conversation_members::conversation_id.eq_any(ids))
+            .select((
+                schema::conversation_members::conversation_id,
+                schema::conversation_members::member,
+            ))
+            .order_by(schema::conversation_members::member.asc())
+    }
+}
+
+type All<DB> = Order<
+    Select<schema::group_messages::table, AsSelect<GroupMessage, DB>>,
+    Desc<schema::group_messages::id>,
+>;
+type WithIds<DB> = Filter<All<DB>, EqAny<schema::group_messages::id, Vec<u64>>>;
+type InConversation<DB> = Filter<All<DB>, Eq<schema::group_messages::conversation_id, u64>>;
+type Limited<DB> = Limit<InConversation<DB>>;
+type After<DB> = Filter<InConversation<DB>, Gt<schema::group_messages::id, u64>>;
+type Before<DB> = Filter<InConversation<DB>, Lt<schema::group_messages::id, u64>>;
+type AfterLimited<DB> = Limit<After<DB>>;
+type BeforeLimited<DB> = Limit<Before<DB>>;
+type LastInserted<DB> = Filter<All<DB>, Eq<schema::group_messages::id, last_insert_id::HelperType>>;
+
+impl GroupMessage {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::group_messages::table
+            .select(Self::as_select())
+            .order_by(schema::group_messages::id.desc())
+    }
+
+    pub fn with_ids<DB: Backend>(ids: Vec<u64>) -> WithIds<DB> {
+        Self::all().filter(schema::group_messages::id.eq_any(ids))
+    }
+
+    pub fn in_conversation<DB: Backend>(id: u64) -> InConversation<DB> {
+        Self::all().filter(schema::group_messages::conversation_id.eq(id))
+    }
+
+    /// The message inserted last on the connection this query is run on.
+    pub fn last_inserted<DB: Backend>() -> LastInserted<DB> {
+        Self::all().filter(schema::group_messages::id.eq(last_insert_id()))
+    }
+
+    pub fn limited<DB: Backend>(conversation: u64, limit: usize) -> Limited<DB> {
+        Self::in_conversation(conversation).limit(limit as i64)
+    }
+
+    pub fn after<DB: Backend>(conversation: u64, id: u64) -> After<DB> {
+        Self::in_conversation(conversation).filter(schema::group_messages::id.gt(id))
+    }
+
+    pub fn before<DB: Backend>(conversation: u64, id: u64) -> Before<DB> {
+        Self::in_conversation(conversation).filter(schema::group_messages::id.lt(id))
+    }
+
+    pub fn after_limited<DB: Backend>(
+        conversation: u64,
+        id: u64,
+        limit: usize,
+    ) -> AfterLimited<DB> {
+        Self::after(conversation, id).limit(limit as i64)
+    }
+
+    pub fn before_limited<DB: Backend>(
+        conversation: u64,
+        id: u64,
+        limit: usize,
+    ) -> BeforeLimited<DB> {
+        Self::before(conversation, id).limit(limit as i64)
+    }
+}
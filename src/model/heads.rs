@@ -0,0 +1,215 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{
+    And, AsSelect, Asc, Desc, Eq, EqAny, Filter, Like, Limit, NeAny, Offset, Order, Select,
+};
+use diesel::mysql::Mysql;
+use diesel::prelude::*;
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery};
+use diesel::sql_types::{Bigint, Integer, Timestamp, Unsigned, Varchar};
+use diesel::{dsl, helper_types};
+
+use super::blocks::{blocked_by, BlockedBy};
+use super::flags::{on_shelf, shelved, OnShelf, Shelf, Shelved};
+use super::schema;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::conversation_heads)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewConversationHead {
+    pub user: String,
+    pub peer: String,
+    pub last_message_id: u64,
+    pub last_activity: NaiveDateTime,
+    pub unread_count: u32,
+}
+
+impl NewConversationHead {
+    /// Inserts the head, or moves an existing one on to the new last message and adds to its
+    /// unread count. Diesel only builds `ON DUPLICATE KEY UPDATE` for tables with a single column
+    /// primary key, which this one doesn't have.
+    pub fn upsert(&self) -> BoxedSqlQuery<'_, Mysql, SqlQuery> {
+        diesel::sql_query(
+            "INSERT INTO conversation_heads \
+                 (user, peer, last_message_id, last_activity, unread_count) \
+             VALUES (?, ?, ?, ?, ?) \
+             ON DUPLICATE KEY UPDATE \
+                 last_message_id = VALUES(last_message_id), \
+                 last_activity = VALUES(last_activity), \
+                 unread_count = unread_count + VALUES(unread_count)",
+        )
+        .into_boxed()
+        .bind::<Varchar, _>(&self.user)
+        .bind::<Varchar, _>(&self.peer)
+        .bind::<Unsigned<Bigint>, _>(self.last_message_id)
+        .bind::<Timestamp, _>(self.last_activity)
+        .bind::<Unsigned<Integer>, _>(self.unread_count)
+    }
+}
+
+/// The state of a conversation between two users as seen by one of them, kept up to date
+/// whenever a message is sent or read so that listing conversations doesn't need to scan
+/// through all messages.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::conversation_heads)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct ConversationHead {
+    pub peer: String,
+    pub last_message_id: u64,
+    pub last_activity: NaiveDateTime,
+    pub unread_count: u32,
+    /// Kept in the list, but without unread counts or updates pushed for new messages.
+    pub muted: bool,
+}
+
+type All<DB> = Select<schema::conversation_heads::table, AsSelect<ConversationHead, DB>>;
+type Between<'a, DB> = Filter<
+    All<DB>,
+    And<
+        Eq<schema::conversation_heads::user, &'a str>,
+        Eq<schema::conversation_heads::peer, &'a str>,
+    >,
+>;
+
+type Matching<'a> = Filter<
+    Filter<
+        schema::conversation_heads::table,
+        And<
+            Eq<schema::conversation_heads::user, &'a str>,
+            Like<schema::conversation_heads::peer, String>,
+        >,
+    >,
+    NeAny<schema::conversation_heads::peer, BlockedBy<'a>>,
+>;
+type Listed<'a, DB> = Select<
+    Filter<Matching<'a>, NeAny<schema::conversation_heads::peer, Shelved<'a>>>,
+    AsSelect<ConversationHead, DB>,
+>;
+type OnShelfAll<'a, DB> = Select<
+    Filter<Matching<'a>, EqAny<schema::conversation_heads::peer, OnShelf<'a>>>,
+    AsSelect<ConversationHead, DB>,
+>;
+type MostRecent<Q> = Offset<
+    Limit<
+        Order<
+            Q,
+            (
+                Desc<schema::conversation_heads::last_activity>,
+                Asc<schema::conversation_heads::peer>,
+            ),
+        >,
+    >,
+>;
+type Alphabetically<Q> = Offset<Limit<Order<Q, Asc<schema::conversation_heads::peer>>>>;
+type NewestMessageId<'a> =
+    Select<Matching<'a>, helper_types::max<schema::conversation_heads::last_message_id>>;
+
+impl ConversationHead {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::conversation_heads::table.select(Self::as_select())
+    }
+
+    /// `user`'s head of the conversation with `peer`, if they ever exchanged any messages.
+    pub fn between<'a, DB: Backend>(user: &'a str, peer: &'a str) -> Between<'a, DB> {
+        Self::all().filter(
+            schema::conversation_heads::user
+                .eq(user)
+                .and(schema::conversation_heads::peer.eq(peer)),
+        )
+    }
+
+    /// `user`'s conversations with peers matching the `LIKE` `pattern`, leaving out the peers
+    /// they blocked.
+    fn matching(user: &str, pattern: String) -> Matching<'_> {
+        schema::conversation_heads::table
+            .filter(
+                schema::conversation_heads::user
+                    .eq(user)
+                    .and(schema::conversation_heads::peer.like(pattern)),
+            )
+            .filter(schema::conversation_heads::peer.ne_all(blocked_by(user)))
+    }
+
+    /// `user`'s conversations with peers matching the `LIKE` `pattern` that they neither pinned
+    /// nor archived.
+    fn listed<DB: Backend>(user: &str, pattern: String) -> Listed<'_, DB> {
+        Self::matching(user, pattern)
+            .filter(schema::conversation_heads::peer.ne_all(shelved(user)))
+            .select(Self::as_select())
+    }
+
+    /// `user`'s conversations with peers matching the `LIKE` `pattern` that they put on `shelf`.
+    fn on_shelf<DB: Backend>(user: &str, pattern: String, shelf: Shelf) -> OnShelfAll<'_, DB> {
+        Self::matching(user, pattern)
+            .filter(schema::conversation_heads::peer.eq_any(on_shelf(user, shelf)))
+            .select(Self::as_select())
+    }
+
+    /// A page of `user`'s conversations with peers matching the `LIKE` `pattern`, most recently
+    /// active first. Pinned and archived conversations are left out.
+    pub fn most_recent<DB: Backend>(
+        user: &str,
+        pattern: String,
+        offset: usize,
+        limit: usize,
+    ) -> MostRecent<Listed<'_, DB>> {
+        Self::listed(user, pattern)
+            .order_by((
+                schema::conversation_heads::last_activity.desc(),
+                schema::conversation_heads::peer.asc(),
+            ))
+            .limit(limit as i64)
+            .offset(offset as i64)
+    }
+
+    /// A page of `user`'s conversations with peers matching the `LIKE` `pattern`, by peer.
+    /// Pinned and archived conversations are left out.
+    pub fn alphabetically<DB: Backend>(
+        user: &str,
+        pattern: String,
+        offset: usize,
+        limit: usize,
+    ) -> Alphabetically<Listed<'_, DB>> {
+        Self::listed(user, pattern)
+            .order_by(schema::conversation_heads::peer.asc())
+            .limit(limit as i64)
+            .offset(offset as i64)
+    }
+
+    /// Like [`Self::most_recent`], but only the conversations on `shelf`.
+    pub fn most_recent_on_shelf<DB: Backend>(
+        user: &str,
+        pattern: String,
+        shelf: Shelf,
+        offset: usize,
+        limit: usize,
+    ) -> MostRecent<OnShelfAll<'_, DB>> {
+        Self::on_shelf(user, pattern, shelf)
+            .order_by((
+                schema::conversation_heads::last_activity.desc(),
+                schema::conversation_heads::peer.asc(),
+            ))
+            .limit(limit as i64)
+            .offset(offset as i64)
+    }
+
+    /// Like [`Self::alphabetically`], but only the conversations on `shelf`.
+    pub fn alphabetically_on_shelf<DB: Backend>(
+        user: &str,
+        pattern: String,
+        shelf: Shelf,
+        offset: usize,
+        limit: usize,
+    ) -> Alphabetically<OnShelfAll<'_, DB>> {
+        Self::on_shelf(user, pattern, shelf)
+            .order_by(schema::conversation_heads::peer.asc())
+            .limit(limit as i64)
+            .offset(offset as i64)
+    }
+
+    /// The ID of the newest message in any of `user`'s conversations with peers matching the
+    /// `LIKE` `pattern`, whether pinned, archived or neither.
+    pub fn newest_message_id(user: &str, pattern: String) -> NewestMessageId<'_> {
+        Self::matching(user, pattern).select(dsl::max(schema::conversation_heads::last_message_id))
+    }
+}
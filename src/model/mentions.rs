@@ -0,0 +1,70 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{count_star, AsSelect, Desc, Eq, Filter, IsNull, Limit, Order, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+/// A user being mentioned in either a direct or a group message.
+#[derive(Insertable)]
+#[diesel(table_name = schema::mentions)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewMention {
+    pub user: String,
+    pub message_id: Option<u64>,
+    pub group_message_id: Option<u64>,
+}
+
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::mentions)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct Mention {
+    pub id: u64,
+    pub message_id: Option<u64>,
+    pub group_message_id: Option<u64>,
+    pub read_at: Option<NaiveDateTime>,
+}
+
+type All<DB> =
+    Order<Select<schema::mentions::table, AsSelect<Mention, DB>>, Desc<schema::mentions::id>>;
+type Of<'a, DB> = Filter<All<DB>, Eq<schema::mentions::user, &'a str>>;
+type Newest<'a, DB> = Limit<Of<'a, DB>>;
+type FindOf<'a, DB> = Filter<Of<'a, DB>, Eq<schema::mentions::id, u64>>;
+type Unread<'a> = Filter<
+    Filter<schema::mentions::table, Eq<schema::mentions::user, &'a str>>,
+    IsNull<schema::mentions::read_at>,
+>;
+type UnreadCount<'a> = Select<Unread<'a>, count_star>;
+
+impl Mention {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::mentions::table
+            .select(Self::as_select())
+            .order_by(schema::mentions::id.desc())
+    }
+
+    /// Mentions of `user`, newest first.
+    pub fn of<DB: Backend>(user: &str) -> Of<'_, DB> {
+        Self::all().filter(schema::mentions::user.eq(user))
+    }
+
+    pub fn newest<DB: Backend>(user: &str, limit: usize) -> Newest<'_, DB> {
+        Self::of(user).limit(limit as i64)
+    }
+
+    /// The mention with the given ID, as long as it is one of `user`.
+    pub fn find_of<DB: Backend>(user: &str, id: u64) -> FindOf<'_, DB> {
+        Self::of(user).filter(schema::mentions::id.eq(id))
+    }
+
+    /// Mentions of `user` they haven't looked at yet, to update or delete.
+    pub fn unread(user: &str) -> Unread<'_> {
+        schema::mentions::table
+            .filter(schema::mentions::user.eq(user))
+            .filter(schema::mentions::read_at.is_null())
+    }
+
+    pub fn unread_count(user: &str) -> UnreadCount<'_> {
+        Self::unread(user).select(count_star())
+    }
+}
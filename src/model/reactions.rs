@@ -0,0 +1,43 @@
+use diesel::backend::Backend;
+use diesel::dsl::{AsSelect, Asc, EqAny, Filter, Order, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::reactions)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewReaction {
+    pub message_id: u64,
+    pub user: String,
+    pub emoji: String,
+}
+
+/// An emoji `user` reacted to a message with; each user may react with several different ones.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::reactions)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct Reaction {
+    pub message_id: u64,
+    pub user: String,
+    pub emoji: String,
+}
+
+type All<DB> = Order<
+    Select<schema::reactions::table, AsSelect<Reaction, DB>>,
+    Asc<schema::reactions::reacted_at>,
+>;
+type OfMessages<DB> = Filter<All<DB>, EqAny<schema::reactions::message_id, Vec<u64>>>;
+
+impl Reaction {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::reactions::table
+            .select(Self::as_select())
+            .order_by(schema::reactions::reacted_at.asc())
+    }
+
+    /// Reactions to any of the messages with the given IDs, oldest first.
+    pub fn of_messages<DB: Backend>(ids: Vec<u64>) -> OfMessages<DB> {
+        Self::all().filter(schema::reactions::message_id.eq_any(ids))
+    }
+}
@@ -0,0 +1,108 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{AsSelect, Asc, Eq, Filter, IsNull, Limit, LtEq, Order, Select};
+use diesel::prelude::*;
+use diesel::{dsl, helper_types};
+
+use super::{schema, NewMessage};
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::scheduled_messages)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewScheduledMessage {
+    pub sender: String,
+    pub receiver: String,
+    pub content: String,
+    pub reply_to: Option<u64>,
+    pub send_at: NaiveDateTime,
+    /// How many minutes the sender's time zone was behind UTC when they scheduled it.
+    pub timezone_offset: i16,
+}
+
+/// A message waiting to be sent at `send_at`, which only moves into `messages` then. Messages that
+/// failed to be sent at `failed_at` wait for the sender to try again.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::scheduled_messages)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct ScheduledMessage {
+    pub id: u64,
+    pub sender: String,
+    pub receiver: String,
+    pub content: String,
+    pub reply_to: Option<u64>,
+    pub send_at: NaiveDateTime,
+    pub timezone_offset: i16,
+    pub failed_at: Option<NaiveDateTime>,
+}
+
+type All<DB> = Order<
+    Select<schema::scheduled_messages::table, AsSelect<ScheduledMessage, DB>>,
+    (
+        Asc<schema::scheduled_messages::send_at>,
+        Asc<schema::scheduled_messages::id>,
+    ),
+>;
+type Of<'a, DB> = Filter<All<DB>, Eq<schema::scheduled_messages::sender, &'a str>>;
+type FindOf<'a, DB> = Filter<Of<'a, DB>, Eq<schema::scheduled_messages::id, u64>>;
+type Pending<DB> = Filter<All<DB>, IsNull<schema::scheduled_messages::failed_at>>;
+type Due<DB> = Limit<Filter<Pending<DB>, LtEq<schema::scheduled_messages::send_at, NaiveDateTime>>>;
+type NextSendAt = Select<
+    Filter<schema::scheduled_messages::table, IsNull<schema::scheduled_messages::failed_at>>,
+    helper_types::min<schema::scheduled_messages::send_at>,
+>;
+
+impl ScheduledMessage {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::scheduled_messages::table
+            .select(Self::as_select())
+            .order_by((
+                schema::scheduled_messages::send_at.asc(),
+                schema::scheduled_messages::id.asc(),
+            ))
+    }
+
+    /// The messages `sender` scheduled, due first.
+    pub fn of<DB: Backend>(sender: &str) -> Of<'_, DB> {
+        Self::all().filter(schema::scheduled_messages::sender.eq(sender))
+    }
+
+    pub fn find_of<DB: Backend>(sender: &str, id: u64) -> FindOf<'_, DB> {
+        Self::of(sender).filter(schema::scheduled_messages::id.eq(id))
+    }
+
+    /// Messages that didn't fail to be sent so far.
+    pub fn pending<DB: Backend>() -> Pending<DB> {
+        Self::all().filter(schema::scheduled_messages::failed_at.is_null())
+    }
+
+    /// Up to `limit` pending messages that are due to be sent `at` some point, whoever scheduled
+    /// them.
+    pub fn due<DB: Backend>(at: NaiveDateTime, limit: usize) -> Due<DB> {
+        Self::pending()
+            .filter(schema::scheduled_messages::send_at.le(at))
+            .limit(limit as i64)
+    }
+
+    /// When the next pending message is due, if any are scheduled at all.
+    pub fn next_send_at() -> NextSendAt {
+        schema::scheduled_messages::table
+            .filter(schema::scheduled_messages::failed_at.is_null())
+            .select(dsl::min(schema::scheduled_messages::send_at))
+    }
+
+    /// When the message is due in the time zone it was scheduled in.
+    pub fn local_send_at(&self) -> NaiveDateTime {
+        self.send_at - chrono::Duration::minutes(self.timezone_offset.into())
+    }
+
+    /// The message as it is sent.
+    pub fn into_message(self) -> NewMessage {
+        NewMessage {
+            sender: self.sender,
+            receiver: self.receiver,
+            content: self.content,
+            reply_to: self.reply_to,
+            notice: false,
+        }
+    }
+}
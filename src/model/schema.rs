@@ -1,5 +1,132 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    attachments (id) {
+        id -> Unsigned<Bigint>,
+        message_id -> Unsigned<Bigint>,
+        #[max_length = 255]
+        file_name -> Varchar,
+        #[max_length = 127]
+        content_type -> Varchar,
+        size -> Unsigned<Bigint>,
+        #[max_length = 64]
+        hash -> Char,
+    }
+}
+
+diesel::table! {
+    blocks (user, peer) {
+        #[max_length = 64]
+        user -> Varchar,
+        #[max_length = 64]
+        peer -> Varchar,
+        blocked_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    conversation_flags (user, peer) {
+        #[max_length = 64]
+        user -> Varchar,
+        #[max_length = 64]
+        peer -> Varchar,
+        pinned -> Bool,
+        archived -> Bool,
+    }
+}
+
+diesel::table! {
+    conversation_heads (user, peer) {
+        #[max_length = 64]
+        user -> Varchar,
+        #[max_length = 64]
+        peer -> Varchar,
+        last_message_id -> Unsigned<Bigint>,
+        last_activity -> Timestamp,
+        unread_count -> Unsigned<Integer>,
+        muted -> Bool,
+    }
+}
+
+diesel::table! {
+    conversation_members (conversation_id, member) {
+        conversation_id -> Unsigned<Bigint>,
+        #[max_length = 64]
+        member -> Varchar,
+        joined_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    conversations (id) {
+        id -> Unsigned<Bigint>,
+        #[max_length = 128]
+        title -> Varchar,
+        #[max_length = 64]
+        created_by -> Varchar,
+        created_at -> Timestamp,
+        last_message_id -> Nullable<Unsigned<Bigint>>,
+        last_activity -> Timestamp,
+    }
+}
+
+diesel::table! {
+    disappearing_timers (user_a, user_b) {
+        #[max_length = 64]
+        user_a -> Varchar,
+        #[max_length = 64]
+        user_b -> Varchar,
+        duration_secs -> Unsigned<Integer>,
+        from_read -> Bool,
+        #[max_length = 64]
+        set_by -> Varchar,
+        set_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    group_messages (id) {
+        id -> Unsigned<Bigint>,
+        conversation_id -> Unsigned<Bigint>,
+        #[max_length = 64]
+        sender -> Varchar,
+        #[max_length = 1024]
+        content -> Varchar,
+        sent_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    hidden_messages (user, message_id) {
+        #[max_length = 64]
+        user -> Varchar,
+        message_id -> Unsigned<Bigint>,
+        hidden_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    mentions (id) {
+        id -> Unsigned<Bigint>,
+        #[max_length = 64]
+        user -> Varchar,
+        message_id -> Nullable<Unsigned<Bigint>>,
+        group_message_id -> Nullable<Unsigned<Bigint>>,
+        mentioned_at -> Timestamp,
+        read_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    message_edits (id) {
+        id -> Unsigned<Bigint>,
+        message_id -> Unsigned<Bigint>,
+        #[max_length = 1024]
+        content -> Varchar,
+        edited_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     messages (id) {
         id -> Unsigned<Bigint>,
@@ -11,5 +138,95 @@ diesel::table! {
         content -> Varchar,
         sent_at -> Timestamp,
         read_at -> Nullable<Timestamp>,
+        edited_at -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        #[max_length = 64]
+        deleted_by -> Nullable<Varchar>,
+        reacted_at -> Nullable<Timestamp>,
+        reply_to -> Nullable<Unsigned<Bigint>>,
+        notice -> Bool,
+        disappear_after -> Nullable<Unsigned<Integer>>,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    reactions (message_id, user, emoji) {
+        message_id -> Unsigned<Bigint>,
+        #[max_length = 64]
+        user -> Varchar,
+        #[max_length = 16]
+        emoji -> Varchar,
+        reacted_at -> Timestamp,
     }
 }
+
+diesel::table! {
+    scheduled_messages (id) {
+        id -> Unsigned<Bigint>,
+        #[max_length = 64]
+        sender -> Varchar,
+        #[max_length = 64]
+        receiver -> Varchar,
+        #[max_length = 1024]
+        content -> Varchar,
+        reply_to -> Nullable<Unsigned<Bigint>>,
+        send_at -> Timestamp,
+        timezone_offset -> Smallint,
+        scheduled_at -> Timestamp,
+        failed_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        #[max_length = 64]
+        id -> Varchar,
+        #[max_length = 64]
+        user -> Varchar,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        expires_at -> Timestamp,
+        #[max_length = 255]
+        user_agent -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    users (username) {
+        #[max_length = 64]
+        username -> Varchar,
+        #[max_length = 255]
+        password_hash -> Varchar,
+        created_at -> Timestamp,
+        last_seen_at -> Nullable<Timestamp>,
+        hide_last_seen -> Bool,
+    }
+}
+
+diesel::joinable!(attachments -> messages (message_id));
+diesel::joinable!(conversation_members -> conversations (conversation_id));
+diesel::joinable!(group_messages -> conversations (conversation_id));
+diesel::joinable!(hidden_messages -> messages (message_id));
+diesel::joinable!(mentions -> group_messages (group_message_id));
+diesel::joinable!(mentions -> messages (message_id));
+diesel::joinable!(message_edits -> messages (message_id));
+diesel::joinable!(reactions -> messages (message_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    attachments,
+    blocks,
+    conversation_flags,
+    conversation_heads,
+    conversation_members,
+    conversations,
+    disappearing_timers,
+    group_messages,
+    hidden_messages,
+    mentions,
+    message_edits,
+    messages,
+    reactions,
+    scheduled_messages,
+    users,
+);
@@ -0,0 +1,68 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{And, AsSelect, Desc, Eq, Filter, Gt, Order, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::sessions)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewSession {
+    pub id: String,
+    pub user: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::sessions)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct Session {
+    pub id: String,
+    pub user: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub user_agent: Option<String>,
+}
+
+type All<DB> = Select<schema::sessions::table, AsSelect<Session, DB>>;
+type Active<'a, DB> = Filter<
+    All<DB>,
+    And<Eq<schema::sessions::id, &'a str>, Gt<schema::sessions::expires_at, NaiveDateTime>>,
+>;
+type OfUser<'a, DB> = Order<
+    Filter<
+        All<DB>,
+        And<Eq<schema::sessions::user, &'a str>, Gt<schema::sessions::expires_at, NaiveDateTime>>,
+    >,
+    Desc<schema::sessions::last_seen_at>,
+>;
+
+impl Session {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::sessions::table.select(Self::as_select())
+    }
+
+    /// The session with the given ID, unless it has expired or has been revoked (deleted).
+    pub fn active<DB: Backend>(id: &str, now: NaiveDateTime) -> Active<'_, DB> {
+        Self::all().filter(
+            schema::sessions::id
+                .eq(id)
+                .and(schema::sessions::expires_at.gt(now)),
+        )
+    }
+
+    pub fn of_user<DB: Backend>(user: &str, now: NaiveDateTime) -> OfUser<'_, DB> {
+        Self::all()
+            .filter(
+                schema::sessions::user
+                    .eq(user)
+                    .and(schema::sessions::expires_at.gt(now)),
+            )
+            .order_by(schema::sessions::last_seen_at.desc())
+    }
+}
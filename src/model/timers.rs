@@ -0,0 +1,110 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{And, AsSelect, Eq, Filter, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::disappearing_timers)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewDisappearingTimer {
+    pub user_a: String,
+    pub user_b: String,
+    pub duration_secs: u32,
+    pub from_read: bool,
+    pub set_by: String,
+}
+
+impl NewDisappearingTimer {
+    pub fn new(
+        (peer, user): (&str, &str),
+        duration_secs: u32,
+        from_read: bool,
+        set_by: &str,
+    ) -> Self {
+        let (user_a, user_b) = ordered((peer, user));
+        Self {
+            user_a: user_a.to_owned(),
+            user_b: user_b.to_owned(),
+            duration_secs,
+            from_read,
+            set_by: set_by.to_owned(),
+        }
+    }
+}
+
+/// How long messages between two users last, counting from when they are sent or, if
+/// `from_read`, from when they are read.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::disappearing_timers)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct DisappearingTimer {
+    pub duration_secs: u32,
+    pub from_read: bool,
+    pub set_by: String,
+    pub set_at: NaiveDateTime,
+}
+
+/// The participants of a conversation in the order timers are keyed by, which has to agree with
+/// the case-insensitive collation the database checks that order under.
+fn ordered<'a>((peer, user): (&'a str, &'a str)) -> (&'a str, &'a str) {
+    let key = |name: &str| (name.to_ascii_lowercase(), name.to_owned());
+    if key(peer) <= key(user) {
+        (peer, user)
+    } else {
+        (user, peer)
+    }
+}
+
+type Of<'a> = Filter<
+    schema::disappearing_timers::table,
+    And<
+        Eq<schema::disappearing_timers::user_a, &'a str>,
+        Eq<schema::disappearing_timers::user_b, &'a str>,
+    >,
+>;
+type Between<'a, DB> = Select<Of<'a>, AsSelect<DisappearingTimer, DB>>;
+
+impl DisappearingTimer {
+    /// The row of the timer of the conversation between `peers`, to update or delete.
+    pub fn of<'a>(peers: (&'a str, &'a str)) -> Of<'a> {
+        let (user_a, user_b) = ordered(peers);
+        schema::disappearing_timers::table.filter(
+            schema::disappearing_timers::user_a
+                .eq(user_a)
+                .and(schema::disappearing_timers::user_b.eq(user_b)),
+        )
+    }
+
+    /// The timer of the conversation between `peers`, if one is set.
+    pub fn between<'a, DB: Backend>(peers: (&'a str, &'a str)) -> Between<'a, DB> {
+        Self::of(peers).select(Self::as_select())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ordered;
+
+    #[test]
+    fn participants_are_ordered_alphabetically() {
+        assert_eq!(ordered(("bob", "alice")), ("alice", "bob"));
+        assert_eq!(ordered(("alice", "bob")), ("alice", "bob"));
+        assert_eq!(ordered(("alice", "alice")), ("alice", "alice"));
+    }
+
+    #[test]
+    fn ordering_ignores_case_like_the_database() {
+        assert_eq!(ordered(("Bob", "alice")), ("alice", "Bob"));
+        assert_eq!(ordered(("alice", "Bob")), ("alice", "Bob"));
+        assert_eq!(ordered(("ALICE", "bob")), ("ALICE", "bob"));
+    }
+
+    #[test]
+    fn ordering_is_the_same_either_way_round() {
+        for (a, b) in [("Zoe", "adam"), ("adam", "Adam"), ("x1", "X2")] {
+            assert_eq!(ordered((a, b)), ordered((b, a)));
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::dsl::{AsSelect, Eq, EqAny, Filter, Select};
+use diesel::prelude::*;
+
+use super::schema;
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::users)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct NewUser {
+    pub username: String,
+    pub password_hash: String,
+}
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = schema::users)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct User {
+    pub username: String,
+    pub password_hash: String,
+    pub hide_last_seen: bool,
+}
+
+/// What others may learn about when a user was last active.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = schema::users)]
+#[diesel(check_for_backend(diesel::mysql::Mysql))]
+pub struct UserPresence {
+    pub username: String,
+    /// Lags behind by up to a minute, the rest is only known in memory.
+    pub last_seen_at: Option<NaiveDateTime>,
+    pub hide_last_seen: bool,
+}
+
+type All<DB> = Select<schema::users::table, AsSelect<User, DB>>;
+type Named<'a, DB> = Filter<All<DB>, Eq<schema::users::username, &'a str>>;
+type WithNames<DB> = Filter<All<DB>, EqAny<schema::users::username, Vec<String>>>;
+
+impl User {
+    pub fn all<DB: Backend>() -> All<DB> {
+        schema::users::table.select(Self::as_select())
+    }
+
+    pub fn named<DB: Backend>(username: &str) -> Named<'_, DB> {
+        Self::all().filter(schema::users::username.eq(username))
+    }
+
+    /// Those of the given users that actually exist.
+    pub fn with_names<DB: Backend>(usernames: Vec<String>) -> WithNames<DB> {
+        Self::all().filter(schema::users::username.eq_any(usernames))
+    }
+}
+
+type AllPresences<DB> = Select<schema::users::table, AsSelect<UserPresence, DB>>;
+type PresenceNamed<'a, DB> = Filter<AllPresences<DB>, Eq<schema::users::username, &'a str>>;
+type PresencesWithNames<DB> = Filter<AllPresences<DB>, EqAny<schema::users::username, Vec<String>>>;
+
+impl UserPresence {
+    pub fn all<DB: Backend>() -> AllPresences<DB> {
+        schema::users::table.select(Self::as_select())
+    }
+
+    pub fn named<DB: Backend>(username: &str) -> PresenceNamed<'_, DB> {
+        Self::all().filter(schema::users::username.eq(username))
+    }
+
+    pub fn with_names<DB: Backend>(usernames: Vec<String>) -> PresencesWithNames<DB> {
+        Self::all().filter(schema::users::username.eq_any(usernames))
+    }
+}